@@ -1,4 +1,5 @@
-use crate::cpu::CPU;
+use crate::bus::Memory;
+use crate::cpu::{CPU, Variant};
 
 #[derive(Debug)]
 pub enum AddressingMode {
@@ -13,11 +14,19 @@ pub enum AddressingMode {
     Indirect,
     IndirectX,
     IndirectY,
+    ZeroPageIndirect,         // 65C02 (zp)
+    AbsoluteIndexedIndirect,  // 65C02 JMP (abs,X)
+    AbsoluteLong,             // 65C816 al
+    AbsoluteLongX,            // 65C816 al,X
+    DirectIndirectLong,       // 65C816 [d]
+    DirectIndirectLongY,      // 65C816 [d],Y
+    StackRelative,            // 65C816 d,S
+    StackRelativeIndirectY,   // 65C816 (d,S),Y
     // Implied - handled inline
     // Accumulator - handled inline
 }
 
-pub fn get_operand_address(cpu: &mut CPU, mode: &AddressingMode) -> (u16, bool) {
+pub fn get_operand_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> (u16, bool) {
     match mode {
         AddressingMode::Absolute => (cpu.fetch_u16(), false),
 
@@ -29,29 +38,48 @@ pub fn get_operand_address(cpu: &mut CPU, mode: &AddressingMode) -> (u16, bool)
         }
         AddressingMode::Indirect => {
             let ptr = cpu.fetch_u16();
+            // The 65C02 fixed the page wrap with an extra cycle, re-reading the high byte
+            if cpu.variant == Variant::Wdc65C02 {
+                cpu.read(cpu.program_counter.wrapping_sub(1));
+            }
 
-            let lo = cpu.bus.read(ptr) as u16;
-            let hi = if ptr & 0x00FF == 0x00FF {
-                cpu.bus.read(ptr & 0xFF00) as u16
+            let lo = cpu.read(ptr) as u16;
+            // NMOS page-wrap bug, fixed on the 65C02
+            let hi = if ptr & 0x00FF == 0x00FF && cpu.variant != Variant::Wdc65C02 {
+                cpu.read(ptr & 0xFF00) as u16
             } else {
-                cpu.bus.read(ptr + 1) as u16
+                cpu.read(ptr.wrapping_add(1)) as u16
             };
             ((hi << 8) | lo, false)
         }
         AddressingMode::IndirectX => {
             let base = cpu.get_operand();
+            cpu.read(base as u16); // Dummy read while X is added
             let ptr = base.wrapping_add(cpu.register_x);
-            let lo = cpu.bus.read(ptr as u16) as u16;
-            let hi = cpu.bus.read(ptr.wrapping_add(1) as u16) as u16;
+            let lo = cpu.read(ptr as u16) as u16;
+            let hi = cpu.read(ptr.wrapping_add(1) as u16) as u16;
             ((hi << 8) | lo, false)
         }
         AddressingMode::IndirectY => {
             let base = cpu.get_operand();
-            let lo = cpu.bus.read(base as u16) as u16;
-            let hi = cpu.bus.read(base.wrapping_add(1) as u16) as u16;
+            let lo = cpu.read(base as u16) as u16;
+            let hi = cpu.read(base.wrapping_add(1) as u16) as u16;
             let deref_base = (hi << 8) | lo;
             let addr = deref_base.wrapping_add(cpu.register_y as u16);
-            (addr, (deref_base & 0xFF00) != (addr & 0xFF00))
+            index_carry(cpu, deref_base, addr)
+        }
+        AddressingMode::ZeroPageIndirect => {
+            let ptr = cpu.get_operand();
+            let lo = cpu.read(ptr as u16) as u16;
+            let hi = cpu.read(ptr.wrapping_add(1) as u16) as u16;
+            ((hi << 8) | lo, false)
+        }
+        AddressingMode::AbsoluteIndexedIndirect => {
+            let ptr = cpu.fetch_u16().wrapping_add(cpu.register_x as u16);
+            cpu.read(cpu.program_counter.wrapping_sub(1)); // Dummy read while X is added
+            let lo = cpu.read(ptr) as u16;
+            let hi = cpu.read(ptr.wrapping_add(1)) as u16;
+            ((hi << 8) | lo, false)
         }
         AddressingMode::Immediate => {
             let addr = cpu.program_counter;
@@ -61,25 +89,248 @@ pub fn get_operand_address(cpu: &mut CPU, mode: &AddressingMode) -> (u16, bool)
         AddressingMode::AbsoluteX => {
             let base = cpu.fetch_u16();
             let addr = base.wrapping_add(cpu.register_x as u16);
-            (addr, (base & 0xFF00) != (addr & 0xFF00))
+            index_carry(cpu, base, addr)
         }
         AddressingMode::AbsoluteY => {
             let base = cpu.fetch_u16();
             let addr = base.wrapping_add(cpu.register_y as u16);
-            (addr, (base & 0xFF00) != (addr & 0xFF00))
+            index_carry(cpu, base, addr)
         }
         AddressingMode::ZeroPage => {
             (cpu.get_operand() as u16, false)
         }
         AddressingMode::ZeroPageX => {
             let pos = cpu.get_operand();
+            cpu.read(pos as u16); // Dummy read while X is added
             let addr = pos.wrapping_add(cpu.register_x) as u16;
             (addr, false)
         }
         AddressingMode::ZeroPageY => {
             let pos = cpu.get_operand();
+            cpu.read(pos as u16); // Dummy read while Y is added
             let addr = pos.wrapping_add(cpu.register_y) as u16;
             (addr, false)
         }
+
+        // 65C816-only modes never show up on the 8-bit chips
+        AddressingMode::AbsoluteLong | AddressingMode::AbsoluteLongX | AddressingMode::DirectIndirectLong |
+        AddressingMode::DirectIndirectLongY | AddressingMode::StackRelative | AddressingMode::StackRelativeIndirectY => {
+            let (addr, page_crossed) = get_long_operand_address(cpu, mode);
+            (addr as u16, page_crossed)
+        }
     }
 }
+
+// Stores and read-modify-write instructions always spend the carry fix-up cycle on the
+// indexed modes, even when no page is crossed, reads only pay for it on a crossing
+pub fn get_write_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> (u16, bool) {
+    let (addr, page_crossed) = get_operand_address(cpu, mode);
+    let indexed = matches!(mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::IndirectY);
+    if indexed && !page_crossed {
+        fix_up_read(cpu, addr);
+    }
+    (addr, page_crossed)
+}
+
+// Indexing adds to the low byte first and carries into the high byte a cycle later
+fn index_carry<B: Memory>(cpu: &mut CPU<B>, base: u16, addr: u16) -> (u16, bool) {
+    let page_crossed = (base & 0xFF00) != (addr & 0xFF00);
+    if page_crossed {
+        fix_up_read(cpu, (base & 0xFF00) | (addr & 0x00FF));
+    }
+    (addr, page_crossed)
+}
+
+// The NMOS part reads from the address the carry hasn't reached yet, which can hit
+// the wrong page. The 65C02 re-reads the last operand byte instead.
+fn fix_up_read<B: Memory>(cpu: &mut CPU<B>, uncarried: u16) {
+    if cpu.variant == Variant::Wdc65C02 {
+        cpu.read(cpu.program_counter.wrapping_sub(1));
+    } else {
+        cpu.read(uncarried);
+    }
+}
+
+// 65C816 version: zero page becomes the relocatable direct page, data accesses go to the
+// data bank, indexing carries into the bank byte and the long modes supply a full 24-bit
+// address. Returns a 24-bit address and whether indexing took an extra cycle, which reads
+// pay for and the table already counts for writes.
+pub fn get_long_operand_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> (u32, bool) {
+    let data_bank = (cpu.data_bank as u32) << 16;
+    let program_bank = (cpu.program_bank as u32) << 16;
+
+    match mode {
+        AddressingMode::Immediate => {
+            let addr = program_bank | cpu.program_counter as u32;
+            cpu.program_counter = cpu.program_counter.wrapping_add(1);
+            (addr, false)
+        }
+        AddressingMode::Relative => {
+            let (addr, page_crossed) = get_operand_address(cpu, mode);
+            (program_bank | addr as u32, page_crossed)
+        }
+
+        AddressingMode::ZeroPage => {
+            let offset = direct_offset(cpu);
+            (direct_address(cpu, offset), false)
+        }
+        AddressingMode::ZeroPageX => {
+            let offset = direct_offset(cpu);
+            internal_cycle(cpu);
+            (direct_address(cpu, offset.wrapping_add(cpu.index_x())), false)
+        }
+        AddressingMode::ZeroPageY => {
+            let offset = direct_offset(cpu);
+            internal_cycle(cpu);
+            (direct_address(cpu, offset.wrapping_add(cpu.index_y())), false)
+        }
+
+        AddressingMode::Absolute => (data_bank | cpu.fetch_u16() as u32, false),
+        AddressingMode::AbsoluteX => {
+            let base = data_bank | cpu.fetch_u16() as u32;
+            index_carry_long(cpu, base, cpu.index_x())
+        }
+        AddressingMode::AbsoluteY => {
+            let base = data_bank | cpu.fetch_u16() as u32;
+            index_carry_long(cpu, base, cpu.index_y())
+        }
+        AddressingMode::AbsoluteLong => (fetch_u24(cpu), false),
+        AddressingMode::AbsoluteLongX => {
+            let base = fetch_u24(cpu);
+            let (addr, _) = index_long(base, cpu.index_x());
+            (addr, false)
+        }
+
+        AddressingMode::IndirectX => {
+            let offset = direct_offset(cpu);
+            internal_cycle(cpu);
+            let offset = offset.wrapping_add(cpu.index_x());
+            (data_bank | read_direct_pointer(cpu, offset) as u32, false)
+        }
+        AddressingMode::IndirectY => {
+            let offset = direct_offset(cpu);
+            let base = data_bank | read_direct_pointer(cpu, offset) as u32;
+            index_carry_long(cpu, base, cpu.index_y())
+        }
+        AddressingMode::ZeroPageIndirect => {
+            let offset = direct_offset(cpu);
+            (data_bank | read_direct_pointer(cpu, offset) as u32, false)
+        }
+        AddressingMode::DirectIndirectLong => {
+            let ptr = cpu.direct_page.wrapping_add(direct_offset(cpu));
+            (read_long_pointer(cpu, ptr), false)
+        }
+        AddressingMode::DirectIndirectLongY => {
+            let ptr = cpu.direct_page.wrapping_add(direct_offset(cpu));
+            let base = read_long_pointer(cpu, ptr);
+            let (addr, _) = index_long(base, cpu.index_y());
+            (addr, false)
+        }
+
+        AddressingMode::StackRelative => {
+            let offset = cpu.get_operand() as u16;
+            internal_cycle(cpu);
+            (stack_relative_address(cpu, offset) as u32, false)
+        }
+        AddressingMode::StackRelativeIndirectY => {
+            let offset = cpu.get_operand() as u16;
+            internal_cycle(cpu);
+            let ptr = stack_relative_address(cpu, offset);
+            let lo = cpu.read(ptr) as u32;
+            let hi = cpu.read(ptr.wrapping_add(1)) as u32;
+            internal_cycle(cpu);
+            let (addr, _) = index_long(data_bank | (hi << 8) | lo, cpu.index_y());
+            (addr, false)
+        }
+
+        // Jump targets stay in the program bank
+        AddressingMode::Indirect => {
+            let ptr = cpu.fetch_u16();
+            let lo = cpu.read(ptr) as u32;
+            let hi = cpu.read(ptr.wrapping_add(1)) as u32;
+            (program_bank | (hi << 8) | lo, false)
+        }
+        AddressingMode::AbsoluteIndexedIndirect => {
+            let ptr = cpu.fetch_u16().wrapping_add(cpu.index_x());
+            internal_cycle(cpu);
+            let lo = cpu.read_long(program_bank | ptr as u32) as u32;
+            let hi = cpu.read_long(program_bank | ptr.wrapping_add(1) as u32) as u32;
+            (program_bank | (hi << 8) | lo, false)
+        }
+    }
+}
+
+// Stores and read-modify-write instructions always spend the indexing cycle
+pub fn get_long_write_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> u32 {
+    let (addr, extra_cycle) = get_long_operand_address(cpu, mode);
+    let indexed = matches!(mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::IndirectY);
+    if indexed && !extra_cycle {
+        cpu.read_long(addr);
+    }
+    addr
+}
+
+// Fetches a direct page offset. A direct page that is not page aligned takes a cycle
+// more to add it in.
+pub fn direct_offset<B: Memory>(cpu: &mut CPU<B>) -> u16 {
+    let offset = cpu.get_operand() as u16;
+    if cpu.direct_page & 0x00FF != 0 {
+        cpu.cycles += 1;
+        internal_cycle(cpu);
+    }
+    offset
+}
+
+// Cycles the 65C816 spends working on an address re-read the last operand byte
+pub fn internal_cycle<B: Memory>(cpu: &mut CPU<B>) {
+    cpu.read_program(cpu.program_counter.wrapping_sub(1));
+}
+
+// Like index_carry(), with the read from the uncarried address going to the data bank.
+// 16-bit index registers always take the extra cycle.
+fn index_carry_long<B: Memory>(cpu: &mut CPU<B>, base: u32, index: u16) -> (u32, bool) {
+    let (addr, page_crossed) = index_long(base, index);
+    let extra_cycle = page_crossed || !cpu.index_8bit();
+    if extra_cycle {
+        cpu.read_long((base & 0xFF_FF00) | (addr & 0x00_00FF));
+    }
+    (addr, extra_cycle)
+}
+
+fn fetch_u24<B: Memory>(cpu: &mut CPU<B>) -> u32 {
+    let lo = cpu.fetch_u16() as u32;
+    let bank = cpu.get_operand() as u32;
+    (bank << 16) | lo
+}
+
+fn index_long(base: u32, index: u16) -> (u32, bool) {
+    let addr = (base + index as u32) & 0xFF_FFFF;
+    (addr, (base & 0xFF_FF00) != (addr & 0xFF_FF00))
+}
+
+// In emulation mode with a page-aligned direct page, direct page indexing wraps
+// inside the page just like zero page on a 6502
+pub fn direct_address<B: Memory>(cpu: &CPU<B>, offset: u16) -> u32 {
+    if cpu.emulation && cpu.direct_page & 0x00FF == 0 {
+        (cpu.direct_page | (offset & 0x00FF)) as u32
+    } else {
+        cpu.direct_page.wrapping_add(offset) as u32
+    }
+}
+
+fn stack_relative_address<B: Memory>(cpu: &CPU<B>, offset: u16) -> u16 {
+    cpu.stack_address().wrapping_add(offset)
+}
+
+fn read_direct_pointer<B: Memory>(cpu: &mut CPU<B>, offset: u16) -> u16 {
+    let lo = cpu.read(direct_address(cpu, offset) as u16) as u16;
+    let hi = cpu.read(direct_address(cpu, offset.wrapping_add(1)) as u16) as u16;
+    (hi << 8) | lo
+}
+
+fn read_long_pointer<B: Memory>(cpu: &mut CPU<B>, ptr: u16) -> u32 {
+    let lo = cpu.read(ptr) as u32;
+    let hi = cpu.read(ptr.wrapping_add(1)) as u32;
+    let bank = cpu.read(ptr.wrapping_add(2)) as u32;
+    (bank << 16) | (hi << 8) | lo
+}
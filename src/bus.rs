@@ -1,3 +1,29 @@
+// Anything the CPU can be wired to (RAM, ROM, memory-mapped I/O chips, mirrors)
+// implements this trait. The CPU core only ever talks to memory through it.
+pub trait Memory {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, data: u8);
+
+    // Side-effect free read for debuggers and the trace logger, so that
+    // inspecting an I/O register never acknowledges or pops anything.
+    fn peek(&self, address: u16) -> u8;
+
+    // 24-bit accesses made by the 65C816. By default the bank byte is dropped, so a
+    // 16-bit system mirrors every bank. Bank 0 must behave exactly like read()/write().
+    fn read_long(&mut self, address: u32) -> u8 {
+        self.read(address as u16)
+    }
+
+    fn write_long(&mut self, address: u32, data: u8) {
+        self.write(address as u16, data)
+    }
+
+    fn peek_long(&self, address: u32) -> u8 {
+        self.peek(address as u16)
+    }
+}
+
+// Default implementation: a flat 64KB block of RAM.
 pub struct Bus {
     pub memory: [u8; 65536],
 }
@@ -8,12 +34,25 @@ impl Bus {
             memory: [0; 65536],
         }
     }
-    pub fn read(&self, address: u16) -> u8 {
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Bus {
+    fn read(&mut self, address: u16) -> u8 {
         // Cast address to usize for array indexing
         self.memory[address as usize]
     }
 
-    pub fn write(&mut self, address: u16, data: u8) {
+    fn write(&mut self, address: u16, data: u8) {
         self.memory[address as usize] = data;
     }
-}
\ No newline at end of file
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+}
@@ -1,6 +1,11 @@
 #![allow(dead_code)]
 
-use crate::bus::Bus;
+use crate::bus::{Bus, Memory};
+use crate::tick::{Access, Replay};
+use crate::rewind::Rewind;
+use crate::history::WriteHistory;
+use crate::input::{Input, Inputs};
+use crate::run::{Stop, StopReason};
 
 pub const FLAG_CARRY: u8     = 0b0000_0001;
 pub const FLAG_ZERO: u8      = 0b0000_0010;
@@ -11,103 +16,791 @@ pub const FLAG_UNUSED: u8    = 0b0010_0000;
 pub const FLAG_OVERFLOW: u8  = 0b0100_0000;
 pub const FLAG_NEGATIVE: u8  = 0b1000_0000;
 
-pub struct CPU {
+// 65C816 native mode reuses bits 4 and 5 as the register width flags (1 = 8-bit)
+pub const FLAG_INDEX_WIDTH: u8  = 0b0001_0000;
+pub const FLAG_MEMORY_WIDTH: u8 = 0b0010_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Nmos6502,  // Original MOS 6502, undocumented opcodes and all
+    Wdc65C02,  // WDC CMOS 65C02: new instructions, bug fixes, NOPs for undefined opcodes
+    Ricoh2A03, // NES CPU: an NMOS 6502 with the decimal mode arithmetic disabled
+    W65C816,   // 16-bit successor, starts in 6502 emulation mode until XCE switches to native
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    Running,
+    Jammed,  // Hit a JAM/KIL opcode, only reset() brings the CPU back
+    Waiting, // 65C02 WAI, sleeping until IRQ or NMI is asserted
+    Stopped, // 65C02 STP, the clock is stopped until reset()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+// What started an interrupt entry, reported to the on_interrupt hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptSource {
+    Irq,
+    Nmi,
+    Brk,
+    Cop, // 65C816 only
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptEvent {
+    pub source: InterruptSource,
+    pub cycle: u64,    // Cycle count the entry sequence started on
+    pub pc: u32,       // PB:PC of the interrupted instruction (BRK/COP: of the BRK/COP itself)
+    pub vector: u16,   // Vector actually fetched, which shows hijacks and 65C816 native vectors
+    pub handler: u16,  // Where the vector pointed
+}
+
+type InterruptHook = Box<dyn FnMut(&InterruptEvent)>;
+
+// What a call to step() did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Executed { opcode: u8, cycles: u64 },           // Ran an instruction
+    Interrupt { kind: Interrupt, cycles: u64 },     // Ran the IRQ or NMI entry sequence
+    Breakpoint { address: u32 },                    // PC is on a breakpoint, nothing ran
+    Waiting,                                        // Asleep in WAI, one cycle went by
+    Halted { cycles: u64 },                         // Held by RDY, nothing ran
+    Jammed,                                         // Hit a JAM opcode, or already had
+    Stopped,                                        // Hit STP, or already had
+}
+
+impl StepOutcome {
+    // False once the CPU will not run again until reset()
+    pub fn can_continue(self) -> bool {
+        !matches!(self, StepOutcome::Jammed | StepOutcome::Stopped)
+    }
+}
+
+// Where an instruction samples the interrupt lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Poll {
+    Normal, // Second to last cycle
+    Early,  // Taken branch that stayed on its page, one cycle earlier
+    Skip,   // BRK and interrupt entry, the handler's first instruction always runs
+}
+
+impl Interrupt {
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::Nmi => 0xFFFA,
+            Interrupt::Irq => 0xFFFE,
+        }
+    }
+}
+
+impl InterruptSource {
+    // 65C816 native mode has its own set at $FFEx, where BRK gets a vector apart from IRQ
+    fn vector(self, native: bool) -> u16 {
+        match (self, native) {
+            (InterruptSource::Nmi, false) => 0xFFFA,
+            (InterruptSource::Irq | InterruptSource::Brk, false) => 0xFFFE,
+            (InterruptSource::Cop, false) => 0xFFF4,
+            (InterruptSource::Nmi, true) => 0xFFEA,
+            (InterruptSource::Irq, true) => 0xFFEE,
+            (InterruptSource::Brk, true) => 0xFFE6,
+            (InterruptSource::Cop, true) => 0xFFE4,
+            (InterruptSource::Reset, _) => 0xFFFC,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<B: Memory = Bus> {
     pub register_a: u8,        // 8-bit Accumulator
     pub register_x: u8,        // 8-bit X Register
     pub register_y: u8,        // 8-bit Y Register
     pub status: u8,            // 8-bit Status Register (P)
     pub stack_pointer: u8,     // 8-bit Stack Pointer (S)
     pub program_counter: u16,  // 16-bit Program Counter (PC)
-    pub bus: Bus,              // The physical connection to Memory
+    pub bus: B,                // The physical connection to Memory
     pub cycles: u64,           // Tracks the number of clock cycles executed
+    pub cycle_debt: u64,       // How far the last run_for_cycles() went past its budget
+    pub state: CpuState,       // Whether the CPU is still executing instructions
+    pub variant: Variant,      // Which member of the 6502 family is being emulated
+
+    // 65C816 only, the 8-bit chips leave these at their reset values
+    pub register_b: u8,          // High byte of the 16-bit accumulator (B)
+    pub register_x_high: u8,     // High bytes of the 16-bit index registers,
+    pub register_y_high: u8,     // always 0 while the X flag selects 8-bit indexes
+    pub stack_pointer_high: u8,  // High byte of S, pinned to $01 in emulation mode
+    pub direct_page: u16,        // Direct page register (D)
+    pub data_bank: u8,           // Data bank register (DBR)
+    pub program_bank: u8,        // Program bank register (PBR/K)
+    pub emulation: bool,         // E flag, always true for the 8-bit chips
+
+    // Interrupt inputs. IRQ is level triggered and shared, every device pulling it
+    // low holds its own bit. NMI is edge triggered: asserting it latches a request.
+    pub irq_line: u32,                        // One bit per device asserting IRQ
+    pub nmi_line: bool,                       // Level on the NMI input (true = asserted)
+    pub nmi_pending: bool,                    // Edge latched, cleared once the NMI is taken
+    pub pending_interrupt: Option<Interrupt>, // Seen at the last instruction boundary, runs next
+    pub so_line: bool,                        // Level on the SO input (true = asserted)
+    pub so_pending: bool,                     // Edge seen partway through an instruction under tick()
+    pub(crate) poll_mask: bool,               // I flag as the poll at the end of this instruction sees it
+    pub(crate) poll: Poll,                    // When this instruction samples the lines
+    pub(crate) nmi_hijacked: bool,            // BRK or IRQ entry that ended up on the NMI vector
+
+    // RDY input and DMA. Either one holds the CPU on its next read cycle.
+    pub rdy: bool,          // false while a device pulls RDY low
+    pub stall_cycles: u64,  // Cycles still owed to a DMA stall
+    pub stall_align: bool,  // The stall has to start on an even cycle
+
+    // step() stops in front of these (PB:PC), and runs the instruction on the next call
+    pub breakpoints: Vec<u32>,
+    pub(crate) breakpoint_hit: Option<u32>,
+    pub(crate) opcode: u8, // Last opcode fetched
+
+    on_interrupt: Option<InterruptHook>,
+    pub(crate) interrupt_event: Option<InterruptEvent>, // Entry made by this instruction, reported once it completes
+
+    pub(crate) replay: Option<Replay>, // Instruction tick() is partway through
+    pub(crate) rewind: Option<Box<Rewind<B>>>, // History for step_back(), when enabled
+    pub(crate) write_history: Option<WriteHistory>, // Who wrote what, when enabled
+    pub(crate) inputs: Option<Inputs>,              // Input recording or playback
 }
 
-impl CPU {
+// Everything an instruction can change inside the CPU, so a half-finished
+// instruction can be rolled back and run again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub program_counter: u16,
+    pub cycles: u64,
+    pub state: CpuState,
+    pub register_b: u8,
+    pub register_x_high: u8,
+    pub register_y_high: u8,
+    pub stack_pointer_high: u8,
+    pub direct_page: u16,
+    pub data_bank: u8,
+    pub program_bank: u8,
+    pub emulation: bool,
+}
+
+impl CPU<Bus> {
     pub fn new() -> Self {
+        CPU::with_bus(Bus::new())
+    }
+
+    pub fn with_variant(variant: Variant) -> Self {
+        let mut cpu = CPU::new();
+        cpu.variant = variant;
+        cpu
+    }
+}
+
+impl<B: Memory> CPU<B> {
+    pub fn with_bus(bus: B) -> Self {
         CPU {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: 0,
-            stack_pointer: 0xFD,
+            status: FLAG_UNUSED,
+            stack_pointer: 0x00, // Power-on value, the reset sequence takes it down to $FD
             program_counter: 0,
-            bus: Bus::new(),
+            bus,
             cycles: 0,
+            cycle_debt: 0,
+            state: CpuState::Running,
+            variant: Variant::Nmos6502,
+            register_b: 0,
+            register_x_high: 0,
+            register_y_high: 0,
+            stack_pointer_high: 0x01,
+            direct_page: 0,
+            data_bank: 0,
+            program_bank: 0,
+            emulation: true,
+            irq_line: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            pending_interrupt: None,
+            so_line: false,
+            so_pending: false,
+            poll_mask: true,
+            poll: Poll::Normal,
+            nmi_hijacked: false,
+            rdy: true,
+            stall_cycles: 0,
+            stall_align: false,
+            breakpoints: Vec::new(),
+            breakpoint_hit: None,
+            opcode: 0,
+            on_interrupt: None,
+            interrupt_event: None,
+            replay: None,
+            rewind: None,
+            write_history: None,
+            inputs: None,
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            status: self.status,
+            stack_pointer: self.stack_pointer,
+            program_counter: self.program_counter,
+            cycles: self.cycles,
+            state: self.state,
+            register_b: self.register_b,
+            register_x_high: self.register_x_high,
+            register_y_high: self.register_y_high,
+            stack_pointer_high: self.stack_pointer_high,
+            direct_page: self.direct_page,
+            data_bank: self.data_bank,
+            program_bank: self.program_bank,
+            emulation: self.emulation,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.register_a = registers.register_a;
+        self.register_x = registers.register_x;
+        self.register_y = registers.register_y;
+        self.status = registers.status;
+        self.stack_pointer = registers.stack_pointer;
+        self.program_counter = registers.program_counter;
+        self.cycles = registers.cycles;
+        self.state = registers.state;
+        self.register_b = registers.register_b;
+        self.register_x_high = registers.register_x_high;
+        self.register_y_high = registers.register_y_high;
+        self.stack_pointer_high = registers.stack_pointer_high;
+        self.direct_page = registers.direct_page;
+        self.data_bank = registers.data_bank;
+        self.program_bank = registers.program_bank;
+        self.emulation = registers.emulation;
+    }
+
+    // Every bus access an instruction makes goes through these, so tick() can
+    // spread them over the cycles they really happen on
+    pub fn read(&mut self, address: u16) -> u8 {
+        match self.replay.as_mut().map(|replay| replay.next_access(false)) {
+            None => {
+                let data = self.bus.read(address);
+                self.input_read(address as u32, data)
+            }
+            Some(Access::Perform) => {
+                let data = self.bus.read(address);
+                let data = self.input_read(address as u32, data);
+                self.replay.as_mut().unwrap().record(data);
+                data
+            }
+            Some(Access::Replay(data)) => data,
+            // Past this cycle's access. The rest of the run is thrown away, so the bus
+            // is left alone and any value will do.
+            Some(Access::Skip) => 0,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        let access = self.replay.as_mut().map(|replay| replay.next_access(true));
+        if matches!(access, Some(Access::Replay(_) | Access::Skip)) {
+            return;
+        }
+        let old = self.write_history.is_some().then(|| self.bus.peek(address));
+        self.bus.write(address, data);
+        if let Some(replay) = self.replay.as_mut() {
+            replay.record(data);
+        }
+        self.wrote(address as u32, old, data);
+    }
+
+    pub fn read_long(&mut self, address: u32) -> u8 {
+        match self.replay.as_mut().map(|replay| replay.next_access(false)) {
+            None => {
+                let data = self.bus.read_long(address);
+                self.input_read(address, data)
+            }
+            Some(Access::Perform) => {
+                let data = self.bus.read_long(address);
+                let data = self.input_read(address, data);
+                self.replay.as_mut().unwrap().record(data);
+                data
+            }
+            Some(Access::Replay(data)) => data,
+            Some(Access::Skip) => 0,
+        }
+    }
+
+    pub fn write_long(&mut self, address: u32, data: u8) {
+        let access = self.replay.as_mut().map(|replay| replay.next_access(true));
+        if matches!(access, Some(Access::Replay(_) | Access::Skip)) {
+            return;
+        }
+        let old = self.write_history.is_some().then(|| self.bus.peek_long(address));
+        self.bus.write_long(address, data);
+        if let Some(replay) = self.replay.as_mut() {
+            replay.record(data);
+        }
+        self.wrote(address, old, data);
+    }
+
+    // A write that reached the bus, for the rewind buffer and the write history
+    fn wrote(&mut self, address: u32, old: Option<u8>, data: u8) {
+        self.log_write(address, data);
+        if let Some(old) = old {
+            self.record_write(address, old, data);
         }
     }
 
     pub fn get_operand(&mut self) -> u8 {
-        let address = self.program_counter;
-        let data = self.bus.read(address);
-        self.program_counter += 1;
+        let data = self.read_program(self.program_counter);
+        self.program_counter = self.program_counter.wrapping_add(1);
         data
     }
 
+    // A read from the program bank, for fetches and the dummy reads around them
+    pub(crate) fn read_program(&mut self, address: u16) -> u8 {
+        if self.program_bank == 0 {
+            self.read(address)
+        } else {
+            self.read_long(((self.program_bank as u32) << 16) | address as u32)
+        }
+    }
+
     pub fn fetch_u16(&mut self) -> u16 {
         let lo = self.get_operand() as u16;
         let hi = self.get_operand() as u16;
         (hi << 8) | lo
     }
 
-    pub fn step(&mut self) {
+    // Runs one instruction, or the interrupt sequence in its place, and reports what happened.
+    // Once jammed or stopped, further steps do nothing.
+    pub fn step(&mut self) -> StepOutcome {
+        self.feed_inputs();
+
+        // Complete an instruction tick() already started
+        if let Some(start) = self.instruction_start() {
+            let interrupt = self.pending_interrupt;
+            self.finish_instruction();
+            if !self.at_instruction_boundary() {
+                return StepOutcome::Halted { cycles: self.cycles - start };
+            }
+            return self.outcome(interrupt, start);
+        }
+
+        match self.state {
+            CpuState::Jammed => return StepOutcome::Jammed,
+            CpuState::Stopped => return StepOutcome::Stopped,
+            _ => {}
+        }
+
+        let start = self.cycles;
+        if !self.ready() {
+            self.cycles += 1;
+            return StepOutcome::Waiting;
+        }
+
+        let address = ((self.program_bank as u32) << 16) | self.program_counter as u32;
+        if self.pending_interrupt.is_none()
+            && self.breakpoints.contains(&address)
+            && self.breakpoint_hit != Some(address)
+        {
+            self.breakpoint_hit = Some(address);
+            return StepOutcome::Breakpoint { address };
+        }
+
+        // RDY and DMA catch the CPU on its opcode fetch. A stall is sat out in one go,
+        // while RDY held low costs a cycle per call, the same as WAI.
+        while self.halt_cycle(false) {
+            if !self.rdy {
+                return StepOutcome::Halted { cycles: self.cycles - start };
+            }
+        }
+        self.breakpoint_hit = None;
+
+        let interrupt = self.pending_interrupt;
+        self.execute_instruction();
+        self.complete_instruction(self.irq_line, self.nmi_pending);
+        self.outcome(interrupt, start)
+    }
+
+    fn outcome(&self, interrupt: Option<Interrupt>, start: u64) -> StepOutcome {
+        let cycles = self.cycles - start;
+        match (interrupt, self.state) {
+            (Some(kind), _) => StepOutcome::Interrupt { kind, cycles },
+            (None, CpuState::Jammed) => StepOutcome::Jammed,
+            (None, CpuState::Stopped) => StepOutcome::Stopped,
+            (None, _) => StepOutcome::Executed { opcode: self.opcode, cycles },
+        }
+    }
+
+    // Checked between instructions. WAI wakes up as soon as either interrupt input is
+    // asserted, even when the I flag then keeps the IRQ itself from being taken.
+    pub(crate) fn ready(&mut self) -> bool {
+        if self.state == CpuState::Waiting && (self.irq_line != 0 || self.nmi_pending) {
+            self.state = CpuState::Running;
+            self.poll_interrupts(self.irq_line, self.nmi_pending);
+        }
+        self.state == CpuState::Running
+    }
+
+    // Spends the coming cycle halted if RDY is low or a stall is owed. The NMOS chips
+    // only stop on reads, a write they have started goes through regardless.
+    pub(crate) fn halt_cycle(&mut self, write: bool) -> bool {
+        if self.rdy && self.stall_cycles == 0 {
+            return false;
+        }
+        if write && matches!(self.variant, Variant::Nmos6502 | Variant::Ricoh2A03) {
+            return false;
+        }
+
+        if self.stall_cycles > 0 {
+            // An aligned stall that starts on an odd cycle waits one more
+            if self.stall_align {
+                self.stall_align = false;
+                self.stall_cycles += self.cycles % 2;
+            }
+            self.stall_cycles -= 1;
+        }
+        self.cycles += 1;
+        true
+    }
+
+    // Wraps up an instruction: reports an interrupt entry it made and polls the lines
+    pub(crate) fn complete_instruction(&mut self, irq_line: u32, nmi_pending: bool) {
+        if let Some(event) = self.interrupt_event.take() {
+            self.report_interrupt(event);
+        }
+        self.poll_interrupts(irq_line, nmi_pending);
+        self.record_boundary();
+    }
+
+    fn report_interrupt(&mut self, event: InterruptEvent) {
+        if let Some(hook) = self.on_interrupt.as_mut() {
+            hook(&event);
+        }
+    }
+
+    // Called with every interrupt, BRK, COP and reset the CPU goes through
+    pub fn set_on_interrupt(&mut self, hook: impl FnMut(&InterruptEvent) + 'static) {
+        self.on_interrupt = Some(Box::new(hook));
+    }
+
+    // Decides at the end of an instruction whether the next thing to run is an interrupt,
+    // from the lines as they were on the cycle the chip polls them
+    pub(crate) fn poll_interrupts(&mut self, irq_line: u32, nmi_pending: bool) {
+        // An NMI that hijacked the entry has been serviced already
+        if self.nmi_hijacked {
+            self.nmi_hijacked = false;
+            self.nmi_pending = false;
+        }
+
+        self.pending_interrupt = if self.poll == Poll::Skip {
+            None
+        } else if nmi_pending && self.nmi_pending {
+            self.nmi_pending = false;
+            Some(Interrupt::Nmi)
+        } else if irq_line != 0 && !self.poll_mask {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        };
+    }
+
+    // Runs the next instruction, or the interrupt sequence in its place
+    pub(crate) fn execute_instruction(&mut self) {
+        self.poll = Poll::Normal;
+        self.nmi_hijacked = false;
+        self.interrupt_event = None;
+        let start = self.cycles;
+        if let Some(history) = self.write_history.as_mut() {
+            history.started(((self.program_bank as u32) << 16) | self.program_counter as u32, start);
+        }
+
+        if let Some(interrupt) = self.pending_interrupt {
+            let source = match interrupt {
+                Interrupt::Nmi => InterruptSource::Nmi,
+                Interrupt::Irq => InterruptSource::Irq,
+            };
+            self.interrupt_sequence(source);
+            self.stamp_interrupt_event(start);
+            return;
+        }
+
+        let interrupt_mask = self.has_flag(FLAG_INTERRUPT);
+
         let opcode = self.get_operand();
-        self.cycles += crate::cycles::OP_CYCLES[opcode as usize] as u64;
+        self.opcode = opcode;
+        let cycle_table = match self.variant {
+            Variant::Nmos6502 | Variant::Ricoh2A03 => &crate::cycles::OP_CYCLES,
+            Variant::Wdc65C02 => &crate::cycles::OP_CYCLES_65C02,
+            Variant::W65C816 => &crate::cycles::OP_CYCLES_65C816,
+        };
+        self.cycles += cycle_table[opcode as usize] as u64;
         crate::opcodes::execute(self, opcode);
+
+        self.stamp_interrupt_event(start);
+
+        // CLI, SEI and PLP change the I flag after the poll, so the new value only counts
+        // from the next instruction on. RTI restores it in time to take effect immediately.
+        self.poll_mask = if matches!(opcode, 0x58 | 0x78 | 0x28) {
+            interrupt_mask
+        } else {
+            self.has_flag(FLAG_INTERRUPT)
+        };
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.state == CpuState::Jammed
+    }
+
+    // The stack lives in page 1, except for a 65C816 in native mode where S is 16 bits wide
+    pub fn stack_address(&self) -> u16 {
+        ((self.stack_pointer_high as u16) << 8) | self.stack_pointer as u16
     }
 
     pub fn push_stack(&mut self, data: u8) {
-        self.bus.write(0x0100 + self.stack_pointer as u16, data);
+        self.write(self.stack_address(), data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        if self.stack_pointer == 0xFF && !self.emulation {
+            self.stack_pointer_high = self.stack_pointer_high.wrapping_sub(1);
+        }
     }
 
     pub fn pop_stack(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
-        self.bus.read(0x0100 + self.stack_pointer as u16)
+        if self.stack_pointer == 0x00 && !self.emulation {
+            self.stack_pointer_high = self.stack_pointer_high.wrapping_add(1);
+        }
+        self.read(self.stack_address())
+    }
+
+    // Full 16-bit index registers, the high byte is 0 on the 8-bit chips
+    pub fn index_x(&self) -> u16 {
+        ((self.register_x_high as u16) << 8) | self.register_x as u16
+    }
+
+    pub fn index_y(&self) -> u16 {
+        ((self.register_y_high as u16) << 8) | self.register_y as u16
+    }
+
+    // Full 16-bit accumulator (C = B:A)
+    pub fn accumulator(&self) -> u16 {
+        ((self.register_b as u16) << 8) | self.register_a as u16
+    }
+
+    // Register widths the M and X flags select. The 8-bit chips always run with E set.
+    pub fn memory_8bit(&self) -> bool {
+        self.emulation || self.has_flag(FLAG_MEMORY_WIDTH)
+    }
+
+    pub fn index_8bit(&self) -> bool {
+        self.emulation || self.has_flag(FLAG_INDEX_WIDTH)
+    }
+
+    // Cold start: A, X, Y and the flags come up cleared and SP at $00, then the reset
+    // sequence runs and leaves SP at $FD. `cycles` starts again from zero.
+    pub fn power_on(&mut self) {
+        self.power_on_with([0, 0, 0, 0, 0]);
+    }
+
+    // Real chips power up with whatever A, X, Y, SP and P the silicon settles on.
+    // This scrambles them from `seed`, so a run can still be repeated.
+    pub fn power_on_random(&mut self, seed: u64) {
+        let mut state = seed.max(1);
+        let mut values = [0; 5];
+        for value in &mut values {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *value = (state >> 32) as u8;
+        }
+        self.power_on_with(values);
     }
 
+    fn power_on_with(&mut self, [a, x, y, sp, status]: [u8; 5]) {
+        self.register_a = a;
+        self.register_x = x;
+        self.register_y = y;
+        self.stack_pointer = sp;
+        self.status = status | FLAG_UNUSED;
+        self.register_b = 0;
+        self.cycles = 0;
+        self.cycle_debt = 0;
+        self.reset();
+        self.restart_rewind(); // `cycles` went back to zero
+    }
+
+    // Drops whatever instruction tick() was in the middle of, and any interrupt that
+    // was about to be taken
+    pub(crate) fn abandon_instruction(&mut self) {
+        self.replay = None;
+        self.interrupt_event = None;
+        self.pending_interrupt = None;
+        self.nmi_pending = false;
+        self.so_pending = false;
+        self.poll = Poll::Normal;
+        self.nmi_hijacked = false;
+        self.breakpoint_hit = None;
+    }
+
+    // Warm reset. The chip runs its interrupt sequence with the three pushes turned into
+    // reads, so SP drops by 3 but nothing is written, and A, X, Y and memory survive.
+    // It takes 7 cycles and only sets I (the CMOS parts also clear D).
     pub fn reset(&mut self) {
-        self.register_a = 0;
-        self.register_x = 0;
-        self.register_y = 0;
-        self.stack_pointer = 0xFD; // Traditional starting point for the stack
+        let pc = ((self.program_bank as u32) << 16) | self.program_counter as u32;
+        let start = self.cycles;
+
+        self.abandon_instruction();
+        self.state = CpuState::Running;
 
-        self.status = FLAG_INTERRUPT | FLAG_UNUSED;
+        // The 65C816 always comes out of reset in emulation mode
+        self.register_x_high = 0;
+        self.register_y_high = 0;
+        self.stack_pointer_high = 0x01;
+        self.direct_page = 0;
+        self.data_bank = 0;
+        self.program_bank = 0;
+        self.emulation = true;
+
+        self.cycles += 7;
+        if self.variant != Variant::W65C816 {
+            self.read(self.program_counter);
+            self.read(self.program_counter);
+        }
+        for _ in 0..3 {
+            self.read(self.stack_address());
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        }
+
+        self.status |= FLAG_INTERRUPT | FLAG_UNUSED;
+        if matches!(self.variant, Variant::Wdc65C02 | Variant::W65C816) {
+            self.status &= !FLAG_DECIMAL;
+        }
 
         // Read where the program starts from these two memory locations
-        let lo = self.bus.read(0xFFFC) as u16;
-        let hi = self.bus.read(0xFFFD) as u16;
+        let lo = self.read(0xFFFC) as u16;
+        let hi = self.read(0xFFFD) as u16;
 
         self.program_counter = (hi << 8) | lo;
+        self.report_interrupt(InterruptEvent {
+            source: InterruptSource::Reset,
+            cycle: start,
+            pc,
+            vector: 0xFFFC,
+            handler: self.program_counter,
+        });
+        self.record_boundary();
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
-        for i in 0..program.len() {
-            self.bus.write(0x8000 + i as u16, program[i]);
+        for (i, byte) in program.iter().enumerate() {
+            self.bus.write(0x8000 + i as u16, *byte);
         }
         self.bus.write(0xFFFC, 0x00);
         self.bus.write(0xFFFD, 0x80);
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> StopReason {
         self.load(program);
-        self.reset();
-        self.run();
+        self.run()
     }
 
-    fn hardware_interrupt(&mut self, vector_addr: u16) {
+    fn stamp_interrupt_event(&mut self, start: u64) {
+        if let Some(event) = self.interrupt_event.as_mut() {
+            event.cycle = start;
+        }
+    }
+
+    // Notes an entry made by the current instruction, to report once it completes
+    pub(crate) fn entered_interrupt(&mut self, source: InterruptSource, pc: u32, vector: u16) {
+        self.interrupt_event = Some(InterruptEvent {
+            source,
+            cycle: 0, // Filled in by execute_instruction()
+            pc,
+            vector,
+            handler: self.program_counter,
+        });
+    }
+
+    // The sequence BRK, COP, IRQ and NMI share. BRK and COP fetch their signature byte and
+    // push B set, a hardware interrupt throws both fetches away and leaves PC on the
+    // interrupted instruction.
+    pub(crate) fn interrupt_sequence(&mut self, source: InterruptSource) {
+        let software = matches!(source, InterruptSource::Brk | InterruptSource::Cop);
+        let pc = if software { self.program_counter.wrapping_sub(1) } else { self.program_counter };
+        let pc = ((self.program_bank as u32) << 16) | pc as u32;
+
+        if software {
+            self.get_operand();
+        } else {
+            self.cycles += 7;
+            self.read_program(self.program_counter);
+            self.read_program(self.program_counter);
+        }
+
+        // 65C816 native mode also saves the program bank
+        let native = !self.emulation;
+        if native {
+            self.cycles += 1;
+            self.push_stack(self.program_bank);
+        }
+        let vector_addr = source.vector(native);
+
         self.push_stack((self.program_counter >> 8) as u8);
         self.push_stack((self.program_counter & 0xFF) as u8);
 
         let mut status = self.status;
-        status |= FLAG_UNUSED;
-        status &= !FLAG_BREAK;
+        if !native {
+            status |= FLAG_UNUSED;
+            status = if software { status | FLAG_BREAK } else { status & !FLAG_BREAK };
+        }
         self.push_stack(status);
 
+        // On the NMOS chips an NMI latched by the time the vector is fetched takes it over.
+        // The pushed B flag is all that still tells a hijacked BRK apart.
+        let vector_addr = if vector_addr == 0xFFFE
+            && matches!(self.variant, Variant::Nmos6502 | Variant::Ricoh2A03)
+            && self.nmi_latched_by(4)
+        {
+            self.nmi_hijacked = true;
+            0xFFFA
+        } else {
+            vector_addr
+        };
+
         self.status |= FLAG_INTERRUPT;
+        self.program_bank = 0;
 
-        let lo = self.bus.read(vector_addr) as u16;
-        let hi = self.bus.read(vector_addr + 1) as u16;
+        // The CMOS parts also leave decimal mode when taking an interrupt
+        if matches!(self.variant, Variant::Wdc65C02 | Variant::W65C816) {
+            self.status &= !FLAG_DECIMAL;
+        }
+
+        let lo = self.read(vector_addr) as u16;
+        let hi = self.read(vector_addr + 1) as u16;
         self.program_counter = (hi << 8) | lo;
+        self.poll = Poll::Skip;
+        self.entered_interrupt(source, pc, vector_addr);
+    }
+
+    // Whether an NMI edge had been latched by the given cycle of the current instruction.
+    // Under tick() that is the line as it was back then, cycles still to come go by its state now.
+    fn nmi_latched_by(&self, cycle: usize) -> bool {
+        self.replay
+            .as_ref()
+            .and_then(|replay| replay.nmi_pending_on(cycle))
+            .unwrap_or(self.nmi_pending)
     }
 
     pub fn has_flag(&self, flag: u8) -> bool {
@@ -122,26 +815,71 @@ impl CPU {
         }
     }
 
-    pub fn nmi(&mut self) {
-        self.hardware_interrupt(0xFFFA);
+    // Asserting SO sets the V flag, which is how the 1541 drive signals a byte is ready.
+    // The 2A03 and 65C816 have no SO pin. Under tick() an edge partway through an
+    // instruction lands once it completes, after whatever the instruction did to V.
+    pub fn set_so(&mut self, asserted: bool) {
+        if !self.host_input(Input::So(asserted)) {
+            return;
+        }
+        if asserted
+            && !self.so_line
+            && matches!(self.variant, Variant::Nmos6502 | Variant::Wdc65C02)
+        {
+            if self.replay.is_some() {
+                self.so_pending = true;
+            } else {
+                self.status |= FLAG_OVERFLOW;
+            }
+        }
+        self.so_line = asserted;
     }
 
-    pub fn irq(&mut self) {
-        if self.status & FLAG_INTERRUPT == 0 {
-            self.hardware_interrupt(0xFFFE);
+    pub fn set_rdy(&mut self, ready: bool) {
+        if !self.host_input(Input::Rdy(ready)) {
+            return;
         }
+        self.rdy = ready;
     }
 
-    pub fn run(&mut self) {
-        self.reset();
-        loop {
-            let previous_pc = self.program_counter;
-            let _opcode = self.bus.read(self.program_counter);
-            self.step();
+    // Halts the CPU for `cycles` cycles from its next read on, the way DMA does. An aligned
+    // stall takes one extra cycle when it starts on an odd cycle (NES OAM DMA).
+    pub fn stall(&mut self, cycles: u64, align: bool) {
+        if !self.host_input(Input::Stall { cycles, align }) {
+            return;
+        }
+        self.stall_cycles += cycles;
+        self.stall_align |= align;
+    }
 
-            if self.program_counter == previous_pc || self.program_counter == 0x0000 {
-                return;
-            }
+    // Devices drive the IRQ input with their own bit of `source`. The line stays
+    // asserted while any of them holds it, and is serviced whenever the I flag allows.
+    pub fn set_irq(&mut self, source: u32, asserted: bool) {
+        if !self.host_input(Input::Irq { source, asserted }) {
+            return;
+        }
+        if asserted {
+            self.irq_line |= source;
+        } else {
+            self.irq_line &= !source;
         }
     }
+
+    // Only the transition to asserted requests an NMI, holding the line does nothing more
+    pub fn set_nmi(&mut self, asserted: bool) {
+        if !self.host_input(Input::Nmi(asserted)) {
+            return;
+        }
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = asserted;
+    }
+
+    // Runs from the reset vector until the program jumps to itself or hits a BRK,
+    // the two ways test programs end
+    pub fn run(&mut self) -> StopReason {
+        self.reset();
+        self.run_until(&mut [Stop::SelfJump, Stop::Brk])
+    }
 }
@@ -0,0 +1,99 @@
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::RangeInclusive;
+
+use crate::bus::Memory;
+use crate::cpu::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteRecord {
+    pub address: u32, // Where the byte went (bank:address)
+    pub pc: u32,      // PB:PC of the instruction that wrote it
+    pub cycle: u64,   // Cycle that instruction started on
+    pub old: u8,      // Byte there before, peeked just ahead of the write
+    pub new: u8,
+}
+
+// Every write the CPU makes, indexed by address, to answer "who last wrote this byte".
+// Only the newest `depth` writes per address are kept.
+pub struct WriteHistory {
+    depth: usize,
+    writes: BTreeMap<u32, VecDeque<WriteRecord>>,
+    pc: u32,    // Instruction being executed
+    cycle: u64,
+}
+
+impl WriteHistory {
+    pub(crate) fn started(&mut self, pc: u32, cycle: u64) {
+        self.pc = pc;
+        self.cycle = cycle;
+    }
+
+    // An instruction that writes the same byte twice (the NMOS read-modify-write dummy
+    // write, a 16-bit value that wraps onto itself) leaves one record with the first old value
+    fn record(&mut self, address: u32, old: u8, new: u8) {
+        let writes = self.writes.entry(address).or_default();
+        if let Some(last) = writes.back_mut().filter(|last| last.cycle == self.cycle && last.pc == self.pc) {
+            last.new = new;
+            return;
+        }
+        if writes.len() == self.depth {
+            writes.pop_front();
+        }
+        writes.push_back(WriteRecord { address, pc: self.pc, cycle: self.cycle, old, new });
+    }
+}
+
+impl<B: Memory> CPU<B> {
+    pub fn enable_write_history(&mut self, depth: usize) {
+        self.write_history = Some(WriteHistory {
+            depth: depth.max(1),
+            writes: BTreeMap::new(),
+            pc: ((self.program_bank as u32) << 16) | self.program_counter as u32,
+            cycle: self.cycles,
+        });
+    }
+
+    pub fn disable_write_history(&mut self) {
+        self.write_history = None;
+    }
+
+    // Writes to one address that are still kept, oldest first
+    pub fn writes_to(&self, address: u32) -> Vec<WriteRecord> {
+        self.writes_in(address..=address)
+    }
+
+    pub fn last_write(&self, address: u32) -> Option<WriteRecord> {
+        self.write_history.as_ref()?.writes.get(&address)?.back().copied()
+    }
+
+    // Writes anywhere in `range`, oldest first
+    pub fn writes_in(&self, range: RangeInclusive<u32>) -> Vec<WriteRecord> {
+        let Some(history) = self.write_history.as_ref() else {
+            return Vec::new();
+        };
+        let mut writes: Vec<WriteRecord> =
+            history.writes.range(range).flat_map(|(_, writes)| writes.iter().copied()).collect();
+        writes.sort_by_key(|write| write.cycle);
+        writes
+    }
+
+    pub(crate) fn record_write(&mut self, address: u32, old: u8, new: u8) {
+        if let Some(history) = self.write_history.as_mut() {
+            history.record(address, old, new);
+        }
+    }
+
+    // Drops writes made by instructions from `cycle` on, after going back in time
+    pub(crate) fn forget_writes_from(&mut self, cycle: u64) {
+        if let Some(history) = self.write_history.as_mut() {
+            for writes in history.writes.values_mut() {
+                while writes.back().is_some_and(|write| write.cycle >= cycle) {
+                    writes.pop_back();
+                }
+            }
+            history.writes.retain(|_, writes| !writes.is_empty());
+        }
+    }
+}
@@ -0,0 +1,259 @@
+#![allow(dead_code)]
+
+use std::ops::RangeInclusive;
+
+use crate::bus::Memory;
+use crate::cpu::CPU;
+use crate::snapshot::{find_chunk, read_chunks, write_chunk, MemorySnapshot, Reader, SnapshotError};
+
+// Input logs use the save state layout with their own magic:
+//
+//   "STAT"  save state the recording starts from
+//   "PORT"  recorded address ranges, u32 first and last
+//   "INPT"  inputs in the order they happened
+const MAGIC: &[u8; 4] = b"R65I";
+pub const INPUT_LOG_VERSION: u16 = 1;
+
+const STATE_CHUNK: [u8; 4] = *b"STAT";
+const PORTS_CHUNK: [u8; 4] = *b"PORT";
+const INPUTS_CHUNK: [u8; 4] = *b"INPT";
+
+// Everything from outside that can steer a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Irq { source: u32, asserted: bool },
+    Nmi(bool),
+    So(bool),
+    Rdy(bool),
+    Stall { cycles: u64, align: bool },
+    Read { address: u32, value: u8 }, // A byte read from one of the recorded ports
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub cycle: u64, // `cycles` when it happened
+    pub input: Input,
+}
+
+// A recording: where it starts and what came in from then on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputLog {
+    pub state: Vec<u8>,
+    pub ports: Vec<RangeInclusive<u32>>,
+    pub events: Vec<InputEvent>,
+}
+
+pub(crate) enum Inputs {
+    Recording(InputLog),
+    Playback {
+        log: InputLog,
+        next_input: usize, // Next line change or stall to feed in
+        next_read: usize,  // Next port read to answer
+        desynced: bool,    // A port read didn't match the recording
+    },
+}
+
+impl InputLog {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ports = Vec::new();
+        for range in &self.ports {
+            ports.extend_from_slice(&range.start().to_le_bytes());
+            ports.extend_from_slice(&range.end().to_le_bytes());
+        }
+
+        let mut inputs = Vec::new();
+        for event in &self.events {
+            inputs.extend_from_slice(&event.cycle.to_le_bytes());
+            match event.input {
+                Input::Irq { source, asserted } => {
+                    inputs.push(0);
+                    inputs.extend_from_slice(&source.to_le_bytes());
+                    inputs.push(asserted as u8);
+                }
+                Input::Nmi(asserted) => inputs.extend_from_slice(&[1, asserted as u8]),
+                Input::So(asserted) => inputs.extend_from_slice(&[2, asserted as u8]),
+                Input::Rdy(ready) => inputs.extend_from_slice(&[3, ready as u8]),
+                Input::Stall { cycles, align } => {
+                    inputs.push(4);
+                    inputs.extend_from_slice(&cycles.to_le_bytes());
+                    inputs.push(align as u8);
+                }
+                Input::Read { address, value } => {
+                    inputs.push(5);
+                    inputs.extend_from_slice(&address.to_le_bytes());
+                    inputs.push(value);
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&INPUT_LOG_VERSION.to_le_bytes());
+        write_chunk(&mut out, STATE_CHUNK, &self.state);
+        write_chunk(&mut out, PORTS_CHUNK, &ports);
+        write_chunk(&mut out, INPUTS_CHUNK, &inputs);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SnapshotError> {
+        if data.len() < 6 || &data[..4] != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version == 0 || version > INPUT_LOG_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let chunks = read_chunks(&data[6..])?;
+        let state = find_chunk(&chunks, STATE_CHUNK)?.to_vec();
+
+        let mut ports = Vec::new();
+        let mut r = Reader(find_chunk(&chunks, PORTS_CHUNK)?);
+        while !r.0.is_empty() {
+            ports.push(r.u32()?..=r.u32()?);
+        }
+
+        let mut events = Vec::new();
+        let mut r = Reader(find_chunk(&chunks, INPUTS_CHUNK)?);
+        while !r.0.is_empty() {
+            let cycle = r.u64()?;
+            let input = match r.u8()? {
+                0 => Input::Irq { source: r.u32()?, asserted: r.bool()? },
+                1 => Input::Nmi(r.bool()?),
+                2 => Input::So(r.bool()?),
+                3 => Input::Rdy(r.bool()?),
+                4 => Input::Stall { cycles: r.u64()?, align: r.bool()? },
+                5 => Input::Read { address: r.u32()?, value: r.u8()? },
+                _ => return Err(SnapshotError::BadValue("input")),
+            };
+            events.push(InputEvent { cycle, input });
+        }
+
+        Ok(InputLog { state, ports, events })
+    }
+}
+
+impl<B: Memory + MemorySnapshot> CPU<B> {
+    // Starts logging the pin inputs, and the bytes read from `ports` (the device registers
+    // whose values come from outside), on top of a save state of where things stand now
+    pub fn start_input_recording(&mut self, ports: &[RangeInclusive<u32>]) -> Result<(), SnapshotError> {
+        let state = self.save_state()?;
+        self.inputs = Some(Inputs::Recording(InputLog { state, ports: ports.to_vec(), events: Vec::new() }));
+        Ok(())
+    }
+
+    // Goes back to where the recording started and feeds its inputs in again. Until
+    // stop_input_playback(), the pins ignore the host and reads from the ports get the
+    // recorded bytes. Drive the CPU with the same step()/tick() calls as when recording.
+    pub fn start_input_playback(&mut self, log: InputLog) -> Result<(), SnapshotError> {
+        self.load_state(&log.state)?;
+        self.inputs = Some(Inputs::Playback { log, next_input: 0, next_read: 0, desynced: false });
+        Ok(())
+    }
+}
+
+impl<B: Memory> CPU<B> {
+    pub fn finish_input_recording(&mut self) -> Option<InputLog> {
+        match self.inputs.take() {
+            Some(Inputs::Recording(log)) => Some(log),
+            inputs => {
+                self.inputs = inputs;
+                None
+            }
+        }
+    }
+
+    pub fn stop_input_playback(&mut self) {
+        if matches!(self.inputs, Some(Inputs::Playback { .. })) {
+            self.inputs = None;
+        }
+    }
+
+    // Every recorded input has been fed in
+    pub fn input_playback_done(&self) -> bool {
+        match &self.inputs {
+            Some(Inputs::Playback { log, next_input, next_read, .. }) => {
+                !log.events[*next_input..].iter().any(|event| !matches!(event.input, Input::Read { .. }))
+                    && !log.events[*next_read..].iter().any(|event| matches!(event.input, Input::Read { .. }))
+            }
+            _ => false,
+        }
+    }
+
+    // The program read a port at a point where the recording had no such read
+    pub fn input_playback_desynced(&self) -> bool {
+        matches!(self.inputs, Some(Inputs::Playback { desynced: true, .. }))
+    }
+
+    // Called by the pin setters. Returns false when playback owns the pins and the
+    // host's change has to be dropped.
+    pub(crate) fn host_input(&mut self, input: Input) -> bool {
+        match self.inputs.as_mut() {
+            Some(Inputs::Recording(log)) => {
+                log.events.push(InputEvent { cycle: self.cycles, input });
+                true
+            }
+            Some(Inputs::Playback { .. }) => false,
+            None => true,
+        }
+    }
+
+    // A byte read from the bus. Recorded if it came from a port, or swapped for the
+    // recorded one during playback.
+    pub(crate) fn input_read(&mut self, address: u32, data: u8) -> u8 {
+        let cycle = self.cycles;
+        match self.inputs.as_mut() {
+            Some(Inputs::Recording(log)) if log.ports.iter().any(|port| port.contains(&address)) => {
+                log.events.push(InputEvent { cycle, input: Input::Read { address, value: data } });
+                data
+            }
+            Some(Inputs::Playback { log, next_read, desynced, .. })
+                if log.ports.iter().any(|port| port.contains(&address)) =>
+            {
+                let next = log.events[*next_read..]
+                    .iter()
+                    .position(|event| matches!(event.input, Input::Read { .. }))
+                    .map(|offset| *next_read + offset);
+                match next.map(|index| (index, log.events[index].input)) {
+                    Some((index, Input::Read { address: recorded, value })) if recorded == address => {
+                        *next_read = index + 1;
+                        value
+                    }
+                    _ => {
+                        *desynced = true;
+                        data
+                    }
+                }
+            }
+            _ => data,
+        }
+    }
+
+    // Applies the recorded pin changes that are due. Runs ahead of every step() and tick().
+    pub(crate) fn feed_inputs(&mut self) {
+        if !matches!(self.inputs, Some(Inputs::Playback { .. })) {
+            return;
+        }
+        let Some(Inputs::Playback { log, mut next_input, next_read, desynced }) = self.inputs.take() else {
+            return;
+        };
+
+        while let Some(event) = log.events.get(next_input) {
+            if !matches!(event.input, Input::Read { .. }) {
+                if event.cycle > self.cycles {
+                    break;
+                }
+                match event.input {
+                    Input::Irq { source, asserted } => self.set_irq(source, asserted),
+                    Input::Nmi(asserted) => self.set_nmi(asserted),
+                    Input::So(asserted) => self.set_so(asserted),
+                    Input::Rdy(ready) => self.set_rdy(ready),
+                    Input::Stall { cycles, align } => self.stall(cycles, align),
+                    Input::Read { .. } => {}
+                }
+            }
+            next_input += 1;
+        }
+        self.inputs = Some(Inputs::Playback { log, next_input, next_read, desynced });
+    }
+}
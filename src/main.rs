@@ -4,10 +4,27 @@ mod opcodes;
 mod addressing;
 mod trace;
 mod cycles;
+mod mos6510;
+mod w65c816;
+mod tick;
+mod run;
+mod snapshot;
+mod rewind;
+mod history;
+mod input;
+mod memory_map;
 
 use std::env;
 use std::fs;
-use crate::cpu::CPU;
+use std::time::{Duration, Instant};
+use crate::bus::Memory;
+use crate::cpu::{CPU, Variant};
+use crate::input::InputLog;
+use crate::memory_map::MemoryMap;
+use crate::run::{Stop, StopReason};
+
+// Writes kept per address for --who-wrote, unless --history-depth says otherwise
+const WHO_WROTE_DEPTH: usize = 8;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -15,7 +32,33 @@ fn main() {
     if args.len() > 1 {
         // CLI
         let is_trace = args.contains(&String::from("--trace"));
-        let filename = args.iter().find(|arg| !arg.starts_with("--") && *arg != &args[0]).unwrap_or(&args[1]);
+        let variant = if args.contains(&String::from("--65c02")) {
+            Variant::Wdc65C02
+        } else if args.contains(&String::from("--2a03")) {
+            Variant::Ricoh2A03
+        } else if args.contains(&String::from("--65c816")) {
+            Variant::W65C816
+        } else {
+            Variant::Nmos6502
+        };
+
+        // Options followed by a value: --load-state FILE, --save-state FILE, --play-inputs FILE,
+        // --cycles N, --who-wrote ADDR or ADDR-ADDR (hex), --history-depth N
+        let option = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1));
+        let load_state = option("--load-state");
+        let save_state = option("--save-state");
+        let play_inputs = option("--play-inputs");
+        let cycle_limit = option("--cycles").and_then(|cycles| cycles.parse::<u64>().ok());
+        let who_wrote = option("--who-wrote").and_then(|range| parse_range(range));
+        let history_depth = option("--history-depth").and_then(|depth| depth.parse::<usize>().ok());
+        let takes_value = |arg: &String| {
+            ["--load-state", "--save-state", "--play-inputs", "--cycles", "--who-wrote", "--history-depth"]
+                .contains(&arg.as_str())
+        };
+
+        let filename = (1..args.len())
+            .find(|&i| !args[i].starts_with("--") && !takes_value(&args[i - 1]))
+            .map_or(&args[1], |i| &args[i]);
 
         println!("Loading binary file: {}", filename);
         
@@ -27,31 +70,93 @@ fn main() {
             }
         };
 
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::with_variant(variant);
+        if who_wrote.is_some() {
+            cpu.enable_write_history(history_depth.unwrap_or(WHO_WROTE_DEPTH));
+        }
 
         for (i, byte) in rom.iter().enumerate() {
             cpu.bus.write(i as u16, *byte);
         }
 
-        // Klaus test starts execution at $0400
+        // Power up as the chip would, then jump straight in, the Klaus test starts at $0400
+        cpu.power_on();
         cpu.program_counter = 0x0400;
+
+        // Pick up from a bookmarked point instead
+        if let Some(path) = load_state {
+            let loaded = fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| cpu.load_state(&data).map_err(|e| e.to_string()));
+            if let Err(e) = loaded {
+                eprintln!("Failed to load save state '{}': {}", path, e);
+                return;
+            }
+            println!("Restored save state {} (cycle {})", path, cpu.cycles);
+        }
+
+        // Or play back a recorded session, which brings its own starting state
+        if let Some(path) = play_inputs {
+            let loaded = fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| InputLog::from_bytes(&data).map_err(|e| e.to_string()))
+                .and_then(|log| cpu.start_input_playback(log).map_err(|e| e.to_string()));
+            if let Err(e) = loaded {
+                eprintln!("Failed to load input log '{}': {}", path, e);
+                return;
+            }
+            println!("Playing back inputs from {} (cycle {})", path, cpu.cycles);
+        }
         
         println!("Starting execution at ${:04X}...", cpu.program_counter);
 
-        // We can't use cpu.run() directly here
-        // so we write a custom run loop that tracks the PC.
-        loop {
-            if is_trace {
-                println!("{}", trace::trace(&mut cpu));
-            }
+        // The Klaus test reports its result by trapping in a JMP * loop
+        let mut stops = vec![Stop::SelfJump];
+        if let Some(cycles) = cycle_limit {
+            stops.push(Stop::Cycles(cycles));
+        }
+        if is_trace {
+            println!("{}", trace::trace(&cpu));
+            stops.push(Stop::When(Box::new(|cpu| {
+                println!("{}", trace::trace(cpu));
+                false
+            })));
+        }
 
-            let previous_pc = cpu.program_counter;
-            cpu.step();
-            
-            if cpu.program_counter == previous_pc {
-                println!("\nExecution trapped in infinite loop at ${:04X}.", cpu.program_counter);
+        match cpu.run_until(&mut stops) {
+            StopReason::SelfJump(address) => {
+                println!("\nExecution trapped in infinite loop at ${:04X}.", address);
                 println!("(Check the test documentation to see if this address means PASS or FAIL).");
-                break;
+            }
+            StopReason::Cycles => println!("\nStopped at ${:04X} after cycle {}.", cpu.program_counter, cpu.cycles),
+            _ => println!("\nCPU halted ({:?}) at ${:04X}.", cpu.state, cpu.program_counter),
+        }
+
+        if play_inputs.is_some() && cpu.input_playback_desynced() {
+            println!("Warning: the program read inputs the recording doesn't have, playback went out of sync.");
+        }
+
+        if let Some((first, last)) = who_wrote {
+            println!("\nWrites to ${:04X}-${:04X}:", first, last);
+            let writes = cpu.writes_in(first..=last);
+            if writes.is_empty() {
+                println!("  (none)");
+            }
+            for write in writes {
+                println!(
+                    "  ${:04X}: ${:02X} -> ${:02X} by the instruction at ${:04X}, cycle {}",
+                    write.address, write.old, write.new, write.pc, write.cycle
+                );
+            }
+        }
+
+        if let Some(path) = save_state {
+            let saved = cpu.save_state()
+                .map_err(|e| e.to_string())
+                .and_then(|data| fs::write(path, data).map_err(|e| e.to_string()));
+            match saved {
+                Ok(()) => println!("Saved state to {}", path),
+                Err(e) => eprintln!("Failed to save state to '{}': {}", path, e),
             }
         }
 
@@ -60,6 +165,14 @@ fn main() {
     }
 }
 
+// "0200" or "0200-02FF"
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first = u32::from_str_radix(first.trim_start_matches('$'), 16).ok()?;
+    let last = u32::from_str_radix(last.trim_start_matches('$'), 16).ok()?;
+    (first <= last).then_some((first, last))
+}
+
 fn run_internal_tests() {
     println!("R6502 Emulator");
 
@@ -208,15 +321,434 @@ fn run_internal_tests() {
     } else {
         println!("  -> FAIL\n");
     }
-}
 
-fn trace_loop(cpu: &mut CPU) {
-    loop {
-        println!("{}", trace::trace(cpu));
-        let previous_pc = cpu.program_counter;
+    // TEST 6
+    cpu = CPU::new();
+    let program6 = vec![
+        0xA9, 0xF0,       // LDA #$F0
+        0xA2, 0x3C,       // LDX #$3C
+        0x87, 0x10,       // SAX $10   (Mem = A & X = $30)
+        0xA7, 0x10,       // LAX $10   (A = X = $30)
+        0xC7, 0x10,       // DCP $10   (Mem = $2F, CMP $2F)
+        0x4C, 0x0A, 0x80  // JMP $800A
+    ];
+
+    cpu.load(program6);
+    cpu.reset();
+
+    println!("Test 6 (Undocumented SAX/LAX/DCP):");
+    println!("--- TRACE LOG ---");
+    trace_loop(&mut cpu);
+    println!("-----------------");
+
+    let mem_val_6 = cpu.bus.read(0x10);
+    let c_flag_6 = (cpu.status & 0b0000_0001) > 0;
+
+    println!("  Final Mem[0x10]: {:02X} (Expected 2F)", mem_val_6);
+    println!("  A: {:02X}, X: {:02X} (Expected A: 30, X: 30)", cpu.register_a, cpu.register_x);
+    println!("  Carry (After DCP): {} (Expected true)", c_flag_6);
+
+    if mem_val_6 == 0x2F && cpu.register_a == 0x30 && cpu.register_x == 0x30 && c_flag_6 {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 7
+    cpu = CPU::new();
+    let program7 = vec![
+        0xA9, 0x42,       // LDA #$42
+        0x8D, 0x00, 0x02, // STA $0200  (4 cycles, the write is the last one)
+        0x4C, 0x05, 0x80  // JMP $8005
+    ];
+
+    cpu.load(program7);
+    cpu.reset();
+    cpu.step();
+
+    println!("Test 7 (Cycle stepping):");
+    let mut write_cycle = 0;
+    for cycle in 1..=4 {
+        cpu.tick();
+        if write_cycle == 0 && cpu.bus.read(0x0200) == 0x42 {
+            write_cycle = cycle;
+        }
+        println!("  Cycle {}: Mem[0x0200] = {:02X}, PC = {:04X}", cycle, cpu.bus.read(0x0200), cpu.program_counter);
+    }
+    println!("  Store landed on cycle {} (Expected 4)", write_cycle);
+
+    if write_cycle == 4 && cpu.at_instruction_boundary() {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 8
+    cpu = CPU::new();
+    let program8 = vec![
+        0x58,             // CLI
+        0xE8,             // INX
+        0x4C, 0x01, 0x80, // JMP $8001
+        0xC8,             // INY  (interrupt handler at $8005)
+        0x40              // RTI
+    ];
+
+    cpu.load(program8);
+    for vector in [0xFFFA, 0xFFFE] {
+        cpu.bus.write(vector, 0x05);
+        cpu.bus.write(vector + 1, 0x80);
+    }
+    cpu.reset();
+
+    println!("Test 8 (Interrupt lines):");
+
+    // NMI fires once on the edge, holding the line does not retrigger it
+    cpu.set_nmi(true);
+    for _ in 0..20 {
+        cpu.step();
+    }
+    let nmi_count = cpu.register_y;
+    println!("  NMI held for 20 steps, handler ran {} time(s) (Expected 1)", nmi_count);
+
+    // IRQ keeps firing while any device still holds the line
+    cpu.set_irq(0b01, true);
+    cpu.set_irq(0b10, true);
+    cpu.set_irq(0b01, false);
+    for _ in 0..20 {
+        cpu.step();
+    }
+    let irq_held = cpu.register_y - nmi_count;
+    cpu.set_irq(0b10, false);
+    cpu.step();
+    cpu.step();
+    let y_released = cpu.register_y;
+    for _ in 0..20 {
+        cpu.step();
+    }
+    println!("  IRQ held by one device, handler ran {} time(s) (Expected > 1)", irq_held);
+    println!("  Handler runs after release: {} (Expected 0)", cpu.register_y - y_released);
+
+    if nmi_count == 1 && irq_held > 1 && cpu.register_y == y_released {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 9
+    cpu = CPU::new();
+    let program9 = vec![
+        0xA2, 0x00,       // LDX #$00
+        0xE8,             // INX
+        0x8A,             // TXA
+        0x95, 0x20,       // STA $20,X
+        0xE0, 0x08,       // CPX #$08
+        0xD0, 0xF8,       // BNE -8
+        0x4C, 0x0A, 0x80  // JMP $800A
+    ];
+
+    cpu.load(program9);
+    cpu.reset();
+
+    println!("Test 9 (Save state round trip):");
+
+    // Bookmark halfway through the loop, then run on to the end
+    for _ in 0..10 {
         cpu.step();
-        if cpu.program_counter == previous_pc || cpu.program_counter == 0x0000 {
-            break;
+    }
+    let state = cpu.save_state().unwrap_or_default();
+    for _ in 0..50 {
+        cpu.step();
+    }
+
+    // A fresh CPU picks up from the bookmark and has to end up in the same place
+    let mut loaded = CPU::new();
+    let load_ok = loaded.load_state(&state).is_ok();
+    let same_bytes = loaded.save_state().is_ok_and(|saved| saved == state);
+    for _ in 0..50 {
+        loaded.step();
+    }
+    let same_end = loaded.registers() == cpu.registers() && loaded.bus.memory == cpu.bus.memory;
+
+    println!("  Snapshot size: {} bytes, loaded: {} (Expected true)", state.len(), load_ok);
+    println!("  Saved again straight after loading, identical: {} (Expected true)", same_bytes);
+    println!("  Ran on 50 steps, X: {:02X}, Mem[0x28]: {:02X} (Expected X: 08, Mem: 08)", loaded.register_x, loaded.bus.read(0x28));
+    println!("  Same registers and memory as the original run: {} (Expected true)", same_end);
+
+    if load_ok && same_bytes && same_end && loaded.register_x == 0x08 && loaded.bus.read(0x28) == 0x08 {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 10
+    cpu = CPU::new();
+    let program10 = vec![
+        0x78,             // SEI
+        0xA2, 0x10,       // LDX #$10
+        0xFE, 0x00, 0x03, // INC $0300,X
+        0xCA,             // DEX
+        0xD0, 0xFA,       // BNE -6
+        0x4C, 0x09, 0x80  // JMP $8009
+    ];
+
+    cpu.load(program10);
+    cpu.reset();
+    cpu.set_irq(0b1, true); // Held but masked, the line level has to come back too
+    cpu.enable_rewind(64, 8);
+
+    println!("Test 10 (Step back):");
+
+    for _ in 0..5 {
+        cpu.step();
+    }
+    let bookmark = cpu.save_state().unwrap_or_default();
+    cpu.set_irq(0b1, false);
+    for _ in 0..40 {
+        cpu.step();
+    }
+    let mem_ahead = cpu.bus.read(0x0308);
+
+    let mut undone = 0;
+    while undone < 40 && cpu.step_back() {
+        undone += 1;
+    }
+    let same_bytes = cpu.save_state().is_ok_and(|saved| saved == bookmark);
+
+    println!("  Mem[0x0308] after 40 more steps: {:02X} (Expected 01)", mem_ahead);
+    println!("  Steps undone: {} (Expected 40)", undone);
+    println!("  Mem[0x0308] after stepping back: {:02X} (Expected 00)", cpu.bus.read(0x0308));
+    println!("  Save state identical to the bookmark: {} (Expected true)", same_bytes);
+
+    if mem_ahead == 0x01 && undone == 40 && cpu.bus.read(0x0308) == 0x00 && same_bytes {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 11
+    cpu = CPU::new();
+    let program11 = vec![
+        0x58,             // CLI
+        0xAD, 0x10, 0xD0, // LDA $D010  (port fed by the host)
+        0x18,             // CLC
+        0x65, 0x10,       // ADC $10
+        0x85, 0x10,       // STA $10
+        0x4C, 0x01, 0x80, // JMP $8001
+        0xE6, 0x11,       // INC $11    (interrupt handler at $800C)
+        0x40              // RTI
+    ];
+
+    cpu.load(program11);
+    cpu.bus.write(0xFFFE, 0x0C);
+    cpu.bus.write(0xFFFF, 0x80);
+    cpu.reset();
+
+    println!("Test 11 (Input recording):");
+
+    // The host plays a device: a new byte on the port every step, an IRQ and a DMA stall
+    let recording = cpu.start_input_recording(&[0xD010..=0xD010]).is_ok();
+    for i in 0..60u8 {
+        cpu.bus.write(0xD010, i.wrapping_mul(7));
+        match i {
+            20 => cpu.set_irq(0b1, true),
+            22 => cpu.set_irq(0b1, false),
+            40 => cpu.stall(7, false),
+            _ => {}
+        }
+        cpu.step();
+        cpu.bus.write(0xD010, 0);
+    }
+    let log = cpu.finish_input_recording().map(|log| log.to_bytes()).unwrap_or_default();
+
+    // Played back on a fresh CPU with the host doing nothing, or the wrong thing
+    let mut replayed = CPU::new();
+    let playback = InputLog::from_bytes(&log).is_ok_and(|log| replayed.start_input_playback(log).is_ok());
+    for i in 0..60 {
+        if i == 10 {
+            replayed.set_irq(0b10, true);
         }
+        replayed.step();
     }
+    let done = replayed.input_playback_done() && !replayed.input_playback_desynced();
+    replayed.stop_input_playback();
+    let same_state = replayed.save_state().ok() == cpu.save_state().ok();
+
+    println!("  Recorded: {}, log size: {} bytes (Expected true)", recording, log.len());
+    println!("  Sum Mem[0x10]: {:02X}, handler runs Mem[0x11]: {} (Expected the same on both runs)", cpu.bus.read(0x10), cpu.bus.read(0x11));
+    println!("  Replayed sum: {:02X}, handler runs: {}", replayed.bus.read(0x10), replayed.bus.read(0x11));
+    println!("  Played back every input in sync: {} (Expected true)", playback && done);
+    println!("  Final save states identical: {} (Expected true)", same_state);
+
+    if recording && playback && done && same_state && cpu.bus.read(0x11) > 0 {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 12
+    let mut map = MemoryMap::new();
+    map.map_ram(0x0000..=0x07FF);
+    map.mirror(0x0800..=0x1FFF, 0x0000..=0x07FF);
+    map.unmap(0x2000..=0x7FFF);
+    map.map_rom(0x8000..=0xFFFF);
+    map.load(0x8000, &[
+        0xA9, 0x42,       // LDA #$42
+        0x8D, 0x05, 0x08, // STA $0805  (mirror of $0005)
+        0x8D, 0x00, 0x80, // STA $8000  (ROM, dropped)
+        0xAD, 0x34, 0x50, // LDA $5034  (unmapped, last byte on the bus was $50)
+        0x85, 0x20,       // STA $20
+        0x4C, 0x0D, 0x80  // JMP $800D
+    ]);
+    map.load(0xFFFC, &[0x00, 0x80]);
+    let mut mapped = CPU::with_bus(map);
+    mapped.reset();
+
+    println!("Test 12 (Memory map):");
+
+    for _ in 0..6 {
+        mapped.step();
+    }
+    let mirrored = mapped.bus.peek(0x0005);
+    let rom = mapped.bus.peek(0x8000);
+    let unmapped = mapped.bus.peek(0x0020);
+
+    println!("  Mem[0x0005] through the mirror: {:02X} (Expected 42)", mirrored);
+    println!("  Mem[0x8000] after writing ROM: {:02X} (Expected A9)", rom);
+    println!("  Read from unmapped $5034: {:02X} (Expected 50)", unmapped);
+
+    // The same loop on the flat Bus and through the map: identical cycles. The speed is
+    // only printed, it depends too much on the machine to pass or fail on.
+    let program12 = vec![
+        0xE8,             // INX
+        0x86, 0x10,       // STX $10
+        0xB5, 0x10,       // LDA $10,X
+        0xFE, 0x00, 0x03, // INC $0300,X
+        0x4C, 0x00, 0x80  // JMP $8000
+    ];
+    cpu = CPU::new();
+    cpu.load(program12.clone());
+    cpu.reset();
+    let mut map = MemoryMap::new();
+    map.mirror(0x0800..=0x1FFF, 0x0000..=0x07FF);
+    map.map_rom(0x8000..=0xFFFF);
+    map.load(0x8000, &program12);
+    map.load(0xFFFC, &[0x00, 0x80]);
+    mapped = CPU::with_bus(map);
+    mapped.reset();
+
+    // Best of five rounds each, taking turns, so a busy machine doesn't skew it
+    let mut flat_time = Duration::MAX;
+    let mut map_time = Duration::MAX;
+    for _ in 0..5 {
+        let start = Instant::now();
+        for _ in 0..40_000 {
+            cpu.step();
+        }
+        flat_time = flat_time.min(start.elapsed());
+        let start = Instant::now();
+        for _ in 0..40_000 {
+            mapped.step();
+        }
+        map_time = map_time.min(start.elapsed());
+    }
+    let ratio = map_time.as_secs_f64() / flat_time.as_secs_f64().max(1e-9);
+
+    println!("  40000 steps, best of 5: Bus {:?}, MemoryMap {:?} ({:.2}x)", flat_time, map_time, ratio);
+    println!("  Cycles: Bus {}, MemoryMap {} (Expected equal)", cpu.cycles, mapped.cycles);
+
+    if mirrored == 0x42 && rom == 0xA9 && unmapped == 0x50 && cpu.registers() == mapped.registers() {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 13
+    let program13 = vec![
+        0xA2, 0x01,       // LDX #$01
+        0xBD, 0xFF, 0x02, // LDA $02FF,X  (page crossed)
+        0x9D, 0xFF, 0x02, // STA $02FF,X
+        0xFE, 0xFF, 0x02, // INC $02FF,X
+        0x06, 0x10,       // ASL $10
+        0x48,             // PHA
+        0x68,             // PLA
+        0xF8,             // SED
+        0x69, 0x05,       // ADC #$05
+        0xD8,             // CLD
+        0x20, 0x1C, 0x80, // JSR $801C
+        0x6C, 0x1D, 0x80, // JMP ($801D)
+        0x4C, 0x19, 0x80, // JMP $8019
+        0x60,             // RTS
+        0x19, 0x80        // ($8019)
+    ];
+
+    println!("Test 13 (65C816 emulation mode cycles):");
+
+    // Coming out of reset the 65C816 is in emulation mode and should take exactly as
+    // long as the NMOS chip for every documented instruction
+    let mut timings = Vec::new();
+    for variant in [Variant::Nmos6502, Variant::W65C816] {
+        cpu = CPU::with_variant(variant);
+        cpu.load(program13.clone());
+        cpu.reset();
+        let mut cycles = Vec::new();
+        while cpu.program_counter != 0x8019 && cycles.len() < 20 {
+            let before = cpu.cycles;
+            cpu.step();
+            cycles.push(cpu.cycles - before);
+        }
+        println!("  {:?}: {:?} (emulation: {})", variant, cycles, cpu.emulation);
+        timings.push(cycles);
+    }
+
+    if timings[0] == timings[1] && timings[0].len() == 13 && cpu.emulation {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+
+    // TEST 14
+    cpu = CPU::with_variant(Variant::Wdc65C02);
+    let mut program14 = vec![0xEA; 0x23];                           // NOP padding
+    program14[0x00..0x03].copy_from_slice(&[0x6C, 0xFF, 0x02]);    // JMP ($02FF)
+    program14[0x10..0x13].copy_from_slice(&[0x6C, 0xFF, 0xFF]);    // JMP ($FFFF)
+    program14[0x20..0x23].copy_from_slice(&[0x4C, 0x20, 0x80]);    // JMP $8020
+
+    cpu.load(program14);
+    cpu.bus.write(0x02FF, 0x10); // ($02FF) -> $8010, the NMOS chip would fetch the high byte from $0200
+    cpu.bus.write(0x0300, 0x80);
+    cpu.bus.write(0x0200, 0x90);
+    cpu.bus.write(0xFFFF, 0x20); // ($FFFF) -> $8020, the high byte comes from $0000
+    cpu.bus.write(0x0000, 0x80);
+    cpu.reset();
+
+    println!("Test 14 (65C02 JMP indirect across a page):");
+    println!("--- TRACE LOG ---");
+    let mut jumps = Vec::new();
+    for _ in 0..2 {
+        println!("{}", trace::trace(&cpu));
+        let before = cpu.cycles;
+        cpu.step();
+        jumps.push((cpu.program_counter, cpu.cycles - before));
+    }
+    println!("-----------------");
+
+    println!("  JMP ($02FF) -> {:04X} in {} cycles (Expected 8010 in 6)", jumps[0].0, jumps[0].1);
+    println!("  JMP ($FFFF) -> {:04X} in {} cycles (Expected 8020 in 6)", jumps[1].0, jumps[1].1);
+
+    if jumps == [(0x8010, 6), (0x8020, 6)] {
+        println!("  -> PASS\n");
+    } else {
+        println!("  -> FAIL\n");
+    }
+}
+
+fn trace_loop(cpu: &mut CPU) {
+    println!("{}", trace::trace(cpu));
+    cpu.run_until(&mut [
+        Stop::SelfJump,
+        Stop::When(Box::new(|cpu| {
+            println!("{}", trace::trace(cpu));
+            false
+        })),
+    ]);
 }
@@ -0,0 +1,239 @@
+#![allow(dead_code)]
+
+use std::any::Any;
+use std::ops::RangeInclusive;
+
+use crate::bus::{Bus, Memory};
+use crate::snapshot::{find_chunk, read_chunks, write_chunk, MemorySnapshot, SnapshotError};
+
+// I/O chips that can be put on a MemoryMap. Anything implementing Memory will do, the
+// Any part only lets the host get its concrete type back with device_mut().
+pub trait Device: Memory + Any {}
+
+impl<T: Memory + Any> Device for T {}
+
+// How a device mapped with map_saved_io() goes into a save state
+type SaveDevice = fn(&dyn Device) -> Vec<u8>;
+type LoadDevice = fn(&mut dyn Device, &[u8]) -> Result<(), SnapshotError>;
+
+// Where one 256-byte page of the address space goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Page {
+    Ram(u8),     // Page of `bus.memory` behind it
+    Rom(u8),     // The same, but writes are dropped
+    Io(u8, u8),  // Device, and which of its pages this is
+    Unmapped,    // Nothing answers, reads see whatever was last on the data bus
+}
+
+// A memory map built around Bus. Every page of the 16-bit address space is routed through a
+// 256-entry table, so an access costs one lookup on top of the flat array. Mirrors are just
+// pages pointing at the same place as others. Everything starts out as plain RAM, which
+// behaves exactly like Bus.
+pub struct MemoryMap {
+    pub bus: Bus, // Backing store for the RAM and ROM pages
+    pages: [Page; 256],
+    devices: Vec<Box<dyn Device>>,
+    saved: Vec<Option<(SaveDevice, LoadDevice)>>, // Per device, None if it keeps its own state
+    open_bus: u8, // Last byte on the data bus
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap {
+            bus: Bus::new(),
+            pages: std::array::from_fn(|page| Page::Ram(page as u8)),
+            devices: Vec::new(),
+            saved: Vec::new(),
+            open_bus: 0,
+        }
+    }
+
+    // Ranges have to start and end on page boundaries, e.g. 0x8000..=0xFFFF
+    fn pages(range: &RangeInclusive<u16>) -> RangeInclusive<usize> {
+        assert!(
+            range.start() & 0xFF == 0 && range.end() & 0xFF == 0xFF,
+            "{:04X}-{:04X} does not cover whole pages",
+            range.start(),
+            range.end()
+        );
+        (*range.start() as usize >> 8)..=(*range.end() as usize >> 8)
+    }
+
+    pub fn map_ram(&mut self, range: RangeInclusive<u16>) {
+        for page in Self::pages(&range) {
+            self.pages[page] = Page::Ram(page as u8);
+        }
+    }
+
+    // Load the contents with load() first, the CPU can't write them
+    pub fn map_rom(&mut self, range: RangeInclusive<u16>) {
+        for page in Self::pages(&range) {
+            self.pages[page] = Page::Rom(page as u8);
+        }
+    }
+
+    // The device sees addresses relative to the start of the range. Returns its index
+    // for device_mut().
+    pub fn map_io(&mut self, range: RangeInclusive<u16>, device: impl Device) -> usize {
+        assert!(self.devices.len() < 256, "too many devices");
+        let index = self.devices.len();
+        self.devices.push(Box::new(device));
+        self.saved.push(None);
+        let pages = Self::pages(&range);
+        let first = *pages.start();
+        for page in pages {
+            self.pages[page] = Page::Io(index as u8, (page - first) as u8);
+        }
+        index
+    }
+
+    // Like map_io(), for a device whose registers should go into save states along with
+    // the RAM
+    pub fn map_saved_io<T: Device + MemorySnapshot>(&mut self, range: RangeInclusive<u16>, device: T) -> usize {
+        let index = self.map_io(range, device);
+        self.saved[index] = Some((
+            |device| (device as &dyn Any).downcast_ref::<T>().unwrap().save_memory(),
+            |device, data| (device as &mut dyn Any).downcast_mut::<T>().unwrap().load_memory(data),
+        ));
+        index
+    }
+
+    // Repeats whatever `source` is mapped to across `range`, the way boards that don't
+    // decode every address line do (the NES maps $0000-$07FF four times up to $1FFF)
+    pub fn mirror(&mut self, range: RangeInclusive<u16>, source: RangeInclusive<u16>) {
+        let source: Vec<Page> = Self::pages(&source).map(|page| self.pages[page]).collect();
+        for (i, page) in Self::pages(&range).enumerate() {
+            self.pages[page] = source[i % source.len()];
+        }
+    }
+
+    pub fn unmap(&mut self, range: RangeInclusive<u16>) {
+        for page in Self::pages(&range) {
+            self.pages[page] = Page::Unmapped;
+        }
+    }
+
+    // Copies bytes into the RAM or ROM behind `address` onwards, ROM included.
+    // I/O and unmapped pages are skipped.
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let address = address.wrapping_add(i as u16);
+            if let Page::Ram(page) | Page::Rom(page) = self.pages[(address >> 8) as usize] {
+                self.bus.memory[((page as usize) << 8) | (address & 0xFF) as usize] = byte;
+            }
+        }
+    }
+
+    pub fn device_mut<T: Device>(&mut self, index: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.devices.get_mut(index)?.as_mut();
+        device.downcast_mut()
+    }
+
+    pub fn device<T: Device>(&self, index: usize) -> Option<&T> {
+        let device: &dyn Any = self.devices.get(index)?.as_ref();
+        device.downcast_ref()
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for MemoryMap {
+    fn read(&mut self, address: u16) -> u8 {
+        let offset = address & 0xFF;
+        let data = match self.pages[(address >> 8) as usize] {
+            Page::Ram(page) | Page::Rom(page) => self.bus.memory[((page as usize) << 8) | offset as usize],
+            Page::Io(device, page) => self.devices[device as usize].read(((page as u16) << 8) | offset),
+            Page::Unmapped => self.open_bus,
+        };
+        self.open_bus = data;
+        data
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        let offset = address & 0xFF;
+        match self.pages[(address >> 8) as usize] {
+            Page::Ram(page) => self.bus.memory[((page as usize) << 8) | offset as usize] = data,
+            Page::Io(device, page) => self.devices[device as usize].write(((page as u16) << 8) | offset, data),
+            Page::Rom(_) | Page::Unmapped => {}
+        }
+        self.open_bus = data;
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        let offset = address & 0xFF;
+        match self.pages[(address >> 8) as usize] {
+            Page::Ram(page) | Page::Rom(page) => self.bus.memory[((page as usize) << 8) | offset as usize],
+            Page::Io(device, page) => self.devices[device as usize].peek(((page as u16) << 8) | offset),
+            Page::Unmapped => self.open_bus,
+        }
+    }
+}
+
+// The RAM and ROM as Bus saves them, the open bus byte, then a chunk per device mapped
+// with map_saved_io() tagged "DEV" and its index. Other devices keep their own state.
+// Memory saved from a plain Bus, or before the rest was added, still loads.
+impl MemorySnapshot for MemoryMap {
+    fn save_memory(&self) -> Vec<u8> {
+        let mut out = self.bus.save_memory();
+        out.push(self.open_bus);
+        for (index, device) in self.devices.iter().enumerate() {
+            if let Some((save, _)) = self.saved[index] {
+                write_chunk(&mut out, device_tag(index), &save(device.as_ref()));
+            }
+        }
+        out
+    }
+
+    // Nothing changes unless all of it loads: the layout and every device chunk are checked
+    // before anything is written, and a device that still rejects its chunk has the ones
+    // loaded before it put back the way they were
+    fn load_memory(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let (memory, rest) = data.split_at(data.len().min(self.bus.memory.len()));
+        if memory.len() != self.bus.memory.len() {
+            return Err(SnapshotError::BadValue("memory size"));
+        }
+        let mut states = Vec::new();
+        if let Some((_, rest)) = rest.split_first() {
+            let chunks = read_chunks(rest)?;
+            for (index, saved) in self.saved.iter().enumerate() {
+                if let Some((save, load)) = *saved {
+                    states.push((index, save, load, find_chunk(&chunks, device_tag(index))?));
+                }
+            }
+        }
+
+        let mut loaded = Vec::new();
+        for &(index, save, load, state) in &states {
+            let previous = save(self.devices[index].as_ref());
+            if let Err(error) = load(self.devices[index].as_mut(), state) {
+                for (index, previous) in loaded.into_iter().chain([(index, previous)]).rev() {
+                    let (_, load) = self.saved[index].unwrap();
+                    let _ = load(self.devices[index].as_mut(), &previous);
+                }
+                return Err(error);
+            }
+            loaded.push((index, previous));
+        }
+
+        self.bus.load_memory(memory)?;
+        if let Some(&open_bus) = rest.first() {
+            self.open_bus = open_bus;
+        }
+        Ok(())
+    }
+
+    // Only RAM took the write in the first place, and devices aren't part of the snapshot
+    fn restore_write(&mut self, address: u32, data: u8) {
+        if let Page::Ram(page) = self.pages[(address as u16 >> 8) as usize] {
+            self.bus.memory[((page as usize) << 8) | (address & 0xFF) as usize] = data;
+        }
+    }
+}
+
+fn device_tag(index: usize) -> [u8; 4] {
+    [b'D', b'E', b'V', index as u8]
+}
@@ -0,0 +1,145 @@
+#![allow(dead_code)]
+
+use crate::bus::Memory;
+
+// Bits 6 and 7 of the 6510 port have no pins. Their input buffers hold whatever was
+// last driven onto them for a while after being switched to input, then fade to 0.
+const UNCONNECTED: u8 = 0b1100_0000;
+
+// Roughly how long the floating bits keep their charge (measured on real C64s, varies by chip)
+pub const FADE_CYCLES: u64 = 350_000;
+
+type PortCallback<B> = Box<dyn FnMut(&mut B, u8)>;
+
+// The 6510's on-chip I/O port, layered over any other bus. $0000 is the data direction
+// register (1 = output) and $0001 the data register, every other address goes to `inner`.
+// A C64 wires P0-P2 to the LORAM/HIRAM/CHAREN banking lines, which the machine can
+// follow with `set_on_change` without the CPU core knowing anything about it.
+pub struct Mos6510Port<B: Memory> {
+    pub inner: B,
+    pub direction: u8,  // $0000
+    pub data: u8,       // $0001 output latch
+    input: u8,          // Levels driven onto the pins from outside (pull-ups, cassette sense...)
+    charge: u8,         // Last level driven onto the floating bits 6 and 7
+    fade_at: [u64; 8],  // Clock at which each floating bit loses its charge
+    clock: u64,         // Bus accesses seen, one per CPU cycle, unless set_clock() says otherwise
+    on_change: Option<PortCallback<B>>,
+}
+
+impl<B: Memory> Mos6510Port<B> {
+    pub fn new(inner: B) -> Self {
+        Mos6510Port {
+            inner,
+            direction: 0,
+            data: 0,
+            // The C64 pulls P0-P5 high, so an all-input port reads back as $3F
+            input: !UNCONNECTED,
+            charge: 0,
+            fade_at: [0; 8],
+            clock: 0,
+            on_change: None,
+        }
+    }
+
+    // Called with the inner bus and the new pin levels whenever the port outputs change
+    pub fn set_on_change(&mut self, callback: impl FnMut(&mut B, u8) + 'static) {
+        self.on_change = Some(Box::new(callback));
+    }
+
+    // Levels on the six real pins: outputs drive their latch bit, inputs follow the outside world
+    pub fn pins(&self) -> u8 {
+        ((self.data & self.direction) | (self.input & !self.direction)) & !UNCONNECTED
+    }
+
+    // Counting accesses misses the cycles the CPU spends without one: halted by RDY or
+    // a DMA stall, waiting in WAI, or jammed. A machine that needs exact fade timing can
+    // pass `cpu.cycles` in before each step() or tick(), the port counts on from there.
+    pub fn set_clock(&mut self, cycles: u64) {
+        self.clock = cycles;
+    }
+
+    // Drive the input pins from outside, e.g. the datasette's sense line on P4
+    pub fn set_input(&mut self, levels: u8) {
+        let old_pins = self.pins();
+        self.input = levels;
+        self.notify(old_pins);
+    }
+
+    // RESET switches every bit back to input
+    pub fn reset(&mut self) {
+        let old_pins = self.pins();
+        self.release_floating_bits(0);
+        self.direction = 0;
+        self.notify(old_pins);
+    }
+
+    fn read_data(&self) -> u8 {
+        let floating = UNCONNECTED & !self.direction;
+        let floating_bits = self.charge & floating & self.fade_mask();
+        self.pins() | (self.data & self.direction & UNCONNECTED) | floating_bits
+    }
+
+    // Floating bits that have not faded yet
+    fn fade_mask(&self) -> u8 {
+        (6..8).filter(|&bit| self.clock < self.fade_at[bit]).fold(0, |mask, bit| mask | (1 << bit))
+    }
+
+    // Start the fade timer on floating bits that stop being driven
+    fn release_floating_bits(&mut self, new_direction: u8) {
+        let released = self.direction & !new_direction & UNCONNECTED;
+        for bit in 6..8 {
+            if released & (1 << bit) != 0 {
+                self.fade_at[bit] = self.clock + FADE_CYCLES;
+            }
+        }
+    }
+
+    fn charge_driven_bits(&mut self) {
+        let driven = self.direction & UNCONNECTED;
+        self.charge = (self.charge & !driven) | (self.data & driven);
+    }
+
+    fn notify(&mut self, old_pins: u8) {
+        let pins = self.pins();
+        if pins != old_pins && let Some(callback) = self.on_change.as_mut() {
+            callback(&mut self.inner, pins);
+        }
+    }
+}
+
+impl<B: Memory> Memory for Mos6510Port<B> {
+    fn read(&mut self, address: u16) -> u8 {
+        self.clock += 1;
+        match address {
+            0x0000 => self.direction,
+            0x0001 => self.read_data(),
+            _ => self.inner.read(address),
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.clock += 1;
+        if address > 0x0001 {
+            self.inner.write(address, data);
+            return;
+        }
+
+        let old_pins = self.pins();
+        if address == 0x0000 {
+            self.release_floating_bits(data);
+            self.direction = data;
+        } else {
+            self.data = data;
+        }
+        self.charge_driven_bits();
+        self.notify(old_pins);
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000 => self.direction,
+            0x0001 => self.read_data(),
+            _ => self.inner.peek(address),
+        }
+    }
+}
@@ -1,54 +1,71 @@
-use crate::cpu::{CPU, FLAG_ZERO, FLAG_NEGATIVE, FLAG_CARRY, FLAG_DECIMAL,
+use crate::cpu::{CPU, CpuState, InterruptSource, Poll, Variant, FLAG_ZERO, FLAG_NEGATIVE, FLAG_CARRY, FLAG_DECIMAL,
                  FLAG_INTERRUPT, FLAG_OVERFLOW, FLAG_UNUSED, FLAG_BREAK};
-use crate::addressing::{AddressingMode, get_operand_address};
+use crate::bus::Memory;
+use crate::addressing::{AddressingMode, get_operand_address, get_write_address, get_long_operand_address,
+                        get_long_write_address};
+
+// One instruction set for the whole family. The 65C816 runs the 65C02 opcodes here too,
+// at the register widths M and X select, and only its new opcodes live in w65c816.rs.
+// The 8-bit chips always have E set, so every width below is 8 bits for them.
+pub fn execute<B: Memory>(cpu: &mut CPU<B>, opcode: u8) {
+    // Single byte instructions still read the byte after the opcode, and throw it away
+    if matches!(opcode & 0x0F, 0x08 | 0x0A) || opcode == 0x40 || opcode == 0x60 {
+        cpu.read_program(cpu.program_counter);
+    }
+
+    if cpu.variant == Variant::W65C816 && crate::w65c816::execute(cpu, opcode) {
+        return;
+    }
+    if matches!(cpu.variant, Variant::Wdc65C02 | Variant::W65C816) && execute_65c02(cpu, opcode) {
+        return;
+    }
 
-pub fn execute(cpu: &mut CPU, opcode: u8) {
     match opcode {
         // BRK
         0x00 => brk(cpu),
 
         // LDA
-        0xA9 => cpu.register_a = load(cpu, &AddressingMode::Immediate),
-        0xA5 => cpu.register_a = load(cpu, &AddressingMode::ZeroPage),
-        0xAD => cpu.register_a = load(cpu, &AddressingMode::Absolute),
-        0xB5 => cpu.register_a = load(cpu, &AddressingMode::ZeroPageX),
-        0xBD => cpu.register_a = load(cpu, &AddressingMode::AbsoluteX),
-        0xB9 => cpu.register_a = load(cpu, &AddressingMode::AbsoluteY),
-        0xA1 => cpu.register_a = load(cpu, &AddressingMode::IndirectX),
-        0xB1 => cpu.register_a = load(cpu, &AddressingMode::IndirectY),
+        0xA9 => lda(cpu, &AddressingMode::Immediate),
+        0xA5 => lda(cpu, &AddressingMode::ZeroPage),
+        0xAD => lda(cpu, &AddressingMode::Absolute),
+        0xB5 => lda(cpu, &AddressingMode::ZeroPageX),
+        0xBD => lda(cpu, &AddressingMode::AbsoluteX),
+        0xB9 => lda(cpu, &AddressingMode::AbsoluteY),
+        0xA1 => lda(cpu, &AddressingMode::IndirectX),
+        0xB1 => lda(cpu, &AddressingMode::IndirectY),
 
         // LDX
-        0xA2 => cpu.register_x = load(cpu, &AddressingMode::Immediate),
-        0xA6 => cpu.register_x = load(cpu, &AddressingMode::ZeroPage),
-        0xAE => cpu.register_x = load(cpu, &AddressingMode::Absolute),
-        0xB6 => cpu.register_x = load(cpu, &AddressingMode::ZeroPageY),
-        0xBE => cpu.register_x = load(cpu, &AddressingMode::AbsoluteY),
+        0xA2 => ldx(cpu, &AddressingMode::Immediate),
+        0xA6 => ldx(cpu, &AddressingMode::ZeroPage),
+        0xAE => ldx(cpu, &AddressingMode::Absolute),
+        0xB6 => ldx(cpu, &AddressingMode::ZeroPageY),
+        0xBE => ldx(cpu, &AddressingMode::AbsoluteY),
 
         // LDY
-        0xA0 => cpu.register_y = load(cpu, &AddressingMode::Immediate),
-        0xA4 => cpu.register_y = load(cpu, &AddressingMode::ZeroPage),
-        0xAC => cpu.register_y = load(cpu, &AddressingMode::Absolute),
-        0xB4 => cpu.register_y = load(cpu, &AddressingMode::ZeroPageX),
-        0xBC => cpu.register_y = load(cpu, &AddressingMode::AbsoluteX),
+        0xA0 => ldy(cpu, &AddressingMode::Immediate),
+        0xA4 => ldy(cpu, &AddressingMode::ZeroPage),
+        0xAC => ldy(cpu, &AddressingMode::Absolute),
+        0xB4 => ldy(cpu, &AddressingMode::ZeroPageX),
+        0xBC => ldy(cpu, &AddressingMode::AbsoluteX),
 
         // STA
-        0x85 => store(cpu, &AddressingMode::ZeroPage, cpu.register_a),
-        0x8D => store(cpu, &AddressingMode::Absolute, cpu.register_a),
-        0x95 => store(cpu, &AddressingMode::ZeroPageX, cpu.register_a),
-        0x9D => store(cpu, &AddressingMode::AbsoluteX, cpu.register_a),
-        0x99 => store(cpu, &AddressingMode::AbsoluteY, cpu.register_a),
-        0x81 => store(cpu, &AddressingMode::IndirectX, cpu.register_a),
-        0x91 => store(cpu, &AddressingMode::IndirectY, cpu.register_a),
+        0x85 => sta(cpu, &AddressingMode::ZeroPage),
+        0x8D => sta(cpu, &AddressingMode::Absolute),
+        0x95 => sta(cpu, &AddressingMode::ZeroPageX),
+        0x9D => sta(cpu, &AddressingMode::AbsoluteX),
+        0x99 => sta(cpu, &AddressingMode::AbsoluteY),
+        0x81 => sta(cpu, &AddressingMode::IndirectX),
+        0x91 => sta(cpu, &AddressingMode::IndirectY),
 
         // STX
-        0x86 => store(cpu, &AddressingMode::ZeroPage, cpu.register_x),
-        0x8E => store(cpu, &AddressingMode::Absolute, cpu.register_x),
-        0x96 => store(cpu, &AddressingMode::ZeroPageY, cpu.register_x),
+        0x86 => stx(cpu, &AddressingMode::ZeroPage),
+        0x8E => stx(cpu, &AddressingMode::Absolute),
+        0x96 => stx(cpu, &AddressingMode::ZeroPageY),
 
         // STY
-        0x84 => store(cpu, &AddressingMode::ZeroPage, cpu.register_y),
-        0x8C => store(cpu, &AddressingMode::Absolute, cpu.register_y),
-        0x94 => store(cpu, &AddressingMode::ZeroPageX, cpu.register_y),
+        0x84 => sty(cpu, &AddressingMode::ZeroPage),
+        0x8C => sty(cpu, &AddressingMode::Absolute),
+        0x94 => sty(cpu, &AddressingMode::ZeroPageX),
 
         // Subroutines
         0x20 => jsr(cpu),
@@ -105,51 +122,51 @@ pub fn execute(cpu: &mut CPU, opcode: u8) {
         0xF1 => sbc(cpu, &AddressingMode::IndirectY),
 
         // ASL
-        0x0A => cpu.register_a = shift_left(cpu, cpu.register_a),
+        0x0A => modify_accumulator(cpu, shift_left),
         0x06 => asl(cpu, &AddressingMode::ZeroPage),
         0x16 => asl(cpu, &AddressingMode::ZeroPageX),
         0x0E => asl(cpu, &AddressingMode::Absolute),
         0x1E => asl(cpu, &AddressingMode::AbsoluteX),
 
         // LSR
-        0x4A => cpu.register_a = shift_right(cpu, cpu.register_a),
+        0x4A => modify_accumulator(cpu, shift_right),
         0x46 => lsr(cpu, &AddressingMode::ZeroPage),
         0x56 => lsr(cpu, &AddressingMode::ZeroPageX),
         0x4E => lsr(cpu, &AddressingMode::Absolute),
         0x5E => lsr(cpu, &AddressingMode::AbsoluteX),
 
         // ROL
-        0x2A => cpu.register_a = rotate_left(cpu, cpu.register_a),
+        0x2A => modify_accumulator(cpu, rotate_left),
         0x26 => rol(cpu, &AddressingMode::ZeroPage),
         0x36 => rol(cpu, &AddressingMode::ZeroPageX),
         0x2E => rol(cpu, &AddressingMode::Absolute),
         0x3E => rol(cpu, &AddressingMode::AbsoluteX),
 
         // ROR
-        0x6A => cpu.register_a = rotate_right(cpu, cpu.register_a),
+        0x6A => modify_accumulator(cpu, rotate_right),
         0x66 => ror(cpu, &AddressingMode::ZeroPage),
         0x76 => ror(cpu, &AddressingMode::ZeroPageX),
         0x6E => ror(cpu, &AddressingMode::Absolute),
         0x7E => ror(cpu, &AddressingMode::AbsoluteX),
 
-        0xC9 => compare(cpu, &AddressingMode::Immediate, cpu.register_a),
-        0xC5 => compare(cpu, &AddressingMode::ZeroPage, cpu.register_a),
-        0xD5 => compare(cpu, &AddressingMode::ZeroPageX, cpu.register_a),
-        0xCD => compare(cpu, &AddressingMode::Absolute, cpu.register_a),
-        0xDD => compare(cpu, &AddressingMode::AbsoluteX, cpu.register_a),
-        0xD9 => compare(cpu, &AddressingMode::AbsoluteY, cpu.register_a),
-        0xC1 => compare(cpu, &AddressingMode::IndirectX, cpu.register_a),
-        0xD1 => compare(cpu, &AddressingMode::IndirectY, cpu.register_a),
+        0xC9 => cmp(cpu, &AddressingMode::Immediate),
+        0xC5 => cmp(cpu, &AddressingMode::ZeroPage),
+        0xD5 => cmp(cpu, &AddressingMode::ZeroPageX),
+        0xCD => cmp(cpu, &AddressingMode::Absolute),
+        0xDD => cmp(cpu, &AddressingMode::AbsoluteX),
+        0xD9 => cmp(cpu, &AddressingMode::AbsoluteY),
+        0xC1 => cmp(cpu, &AddressingMode::IndirectX),
+        0xD1 => cmp(cpu, &AddressingMode::IndirectY),
 
         // CPX
-        0xE0 => compare(cpu, &AddressingMode::Immediate, cpu.register_x),
-        0xE4 => compare(cpu, &AddressingMode::ZeroPage, cpu.register_x),
-        0xEC => compare(cpu, &AddressingMode::Absolute, cpu.register_x),
+        0xE0 => compare_index(cpu, &AddressingMode::Immediate, cpu.index_x()),
+        0xE4 => compare_index(cpu, &AddressingMode::ZeroPage, cpu.index_x()),
+        0xEC => compare_index(cpu, &AddressingMode::Absolute, cpu.index_x()),
 
         // CPY
-        0xC0 => compare(cpu, &AddressingMode::Immediate, cpu.register_y),
-        0xC4 => compare(cpu, &AddressingMode::ZeroPage, cpu.register_y),
-        0xCC => compare(cpu, &AddressingMode::Absolute, cpu.register_y),
+        0xC0 => compare_index(cpu, &AddressingMode::Immediate, cpu.index_y()),
+        0xC4 => compare_index(cpu, &AddressingMode::ZeroPage, cpu.index_y()),
+        0xCC => compare_index(cpu, &AddressingMode::Absolute, cpu.index_y()),
 
         // DEC
         0xC6 => dec(cpu, &AddressingMode::ZeroPage),
@@ -180,19 +197,20 @@ pub fn execute(cpu: &mut CPU, opcode: u8) {
         0x4C => jmp(cpu, &AddressingMode::Absolute),
         0x6C => jmp(cpu, &AddressingMode::Indirect),
 
-        0xAA => tax(cpu),
-        0xA8 => tay(cpu),
-        0x8A => txa(cpu),
-        0x98 => tya(cpu),
-        0x9A => txs(cpu),
-        0xBA => tsx(cpu),
+        // Transfers. The width of the destination decides how much moves.
+        0xAA => set_index_x(cpu, cpu.accumulator()),
+        0xA8 => set_index_y(cpu, cpu.accumulator()),
+        0x8A => transfer_to_accumulator(cpu, cpu.index_x()),
+        0x98 => transfer_to_accumulator(cpu, cpu.index_y()),
+        0x9A => set_stack(cpu, cpu.index_x()),
+        0xBA => set_index_x(cpu, cpu.stack_address()),
 
-        0xE8 => inx(cpu),
-        0xC8 => iny(cpu),
-        0xCA => dex(cpu),
-        0x88 => dey(cpu),
+        0xE8 => set_index_x(cpu, cpu.index_x().wrapping_add(1)),
+        0xC8 => set_index_y(cpu, cpu.index_y().wrapping_add(1)),
+        0xCA => set_index_x(cpu, cpu.index_x().wrapping_sub(1)),
+        0x88 => set_index_y(cpu, cpu.index_y().wrapping_sub(1)),
 
-        0x48 => pha(cpu),
+        0x48 => push_register(cpu, cpu.accumulator(), !cpu.memory_8bit()),
         0x08 => php(cpu),
         0x68 => pla(cpu),
         0x28 => plp(cpu),
@@ -207,321 +225,745 @@ pub fn execute(cpu: &mut CPU, opcode: u8) {
 
         // NOP
         0xEA => { /* Do nothing */ }
-        _ => { }
+
+        // Undocumented NMOS opcodes
+
+        // SLO (ASL + ORA)
+        0x07 => slo(cpu, &AddressingMode::ZeroPage),
+        0x17 => slo(cpu, &AddressingMode::ZeroPageX),
+        0x0F => slo(cpu, &AddressingMode::Absolute),
+        0x1F => slo(cpu, &AddressingMode::AbsoluteX),
+        0x1B => slo(cpu, &AddressingMode::AbsoluteY),
+        0x03 => slo(cpu, &AddressingMode::IndirectX),
+        0x13 => slo(cpu, &AddressingMode::IndirectY),
+
+        // RLA (ROL + AND)
+        0x27 => rla(cpu, &AddressingMode::ZeroPage),
+        0x37 => rla(cpu, &AddressingMode::ZeroPageX),
+        0x2F => rla(cpu, &AddressingMode::Absolute),
+        0x3F => rla(cpu, &AddressingMode::AbsoluteX),
+        0x3B => rla(cpu, &AddressingMode::AbsoluteY),
+        0x23 => rla(cpu, &AddressingMode::IndirectX),
+        0x33 => rla(cpu, &AddressingMode::IndirectY),
+
+        // SRE (LSR + EOR)
+        0x47 => sre(cpu, &AddressingMode::ZeroPage),
+        0x57 => sre(cpu, &AddressingMode::ZeroPageX),
+        0x4F => sre(cpu, &AddressingMode::Absolute),
+        0x5F => sre(cpu, &AddressingMode::AbsoluteX),
+        0x5B => sre(cpu, &AddressingMode::AbsoluteY),
+        0x43 => sre(cpu, &AddressingMode::IndirectX),
+        0x53 => sre(cpu, &AddressingMode::IndirectY),
+
+        // RRA (ROR + ADC)
+        0x67 => rra(cpu, &AddressingMode::ZeroPage),
+        0x77 => rra(cpu, &AddressingMode::ZeroPageX),
+        0x6F => rra(cpu, &AddressingMode::Absolute),
+        0x7F => rra(cpu, &AddressingMode::AbsoluteX),
+        0x7B => rra(cpu, &AddressingMode::AbsoluteY),
+        0x63 => rra(cpu, &AddressingMode::IndirectX),
+        0x73 => rra(cpu, &AddressingMode::IndirectY),
+
+        // SAX (store A & X)
+        0x87 => store(cpu, &AddressingMode::ZeroPage, (cpu.register_a & cpu.register_x) as u16, false),
+        0x97 => store(cpu, &AddressingMode::ZeroPageY, (cpu.register_a & cpu.register_x) as u16, false),
+        0x8F => store(cpu, &AddressingMode::Absolute, (cpu.register_a & cpu.register_x) as u16, false),
+        0x83 => store(cpu, &AddressingMode::IndirectX, (cpu.register_a & cpu.register_x) as u16, false),
+
+        // LAX (LDA + LDX)
+        0xA7 => lax(cpu, &AddressingMode::ZeroPage),
+        0xB7 => lax(cpu, &AddressingMode::ZeroPageY),
+        0xAF => lax(cpu, &AddressingMode::Absolute),
+        0xBF => lax(cpu, &AddressingMode::AbsoluteY),
+        0xA3 => lax(cpu, &AddressingMode::IndirectX),
+        0xB3 => lax(cpu, &AddressingMode::IndirectY),
+
+        // DCP (DEC + CMP)
+        0xC7 => dcp(cpu, &AddressingMode::ZeroPage),
+        0xD7 => dcp(cpu, &AddressingMode::ZeroPageX),
+        0xCF => dcp(cpu, &AddressingMode::Absolute),
+        0xDF => dcp(cpu, &AddressingMode::AbsoluteX),
+        0xDB => dcp(cpu, &AddressingMode::AbsoluteY),
+        0xC3 => dcp(cpu, &AddressingMode::IndirectX),
+        0xD3 => dcp(cpu, &AddressingMode::IndirectY),
+
+        // ISC (INC + SBC)
+        0xE7 => isc(cpu, &AddressingMode::ZeroPage),
+        0xF7 => isc(cpu, &AddressingMode::ZeroPageX),
+        0xEF => isc(cpu, &AddressingMode::Absolute),
+        0xFF => isc(cpu, &AddressingMode::AbsoluteX),
+        0xFB => isc(cpu, &AddressingMode::AbsoluteY),
+        0xE3 => isc(cpu, &AddressingMode::IndirectX),
+        0xF3 => isc(cpu, &AddressingMode::IndirectY),
+
+        // Immediate-only combinations
+        0x0B | 0x2B => anc(cpu),
+        0x4B => alr(cpu),
+        0x6B => arr(cpu),
+        0x8B => ane(cpu),
+        0xAB => lxa(cpu),
+        0xCB => sbx(cpu),
+        0xEB => sbc(cpu, &AddressingMode::Immediate),
+
+        // LAS (mem & SP into A, X and SP)
+        0xBB => las(cpu),
+
+        // SHA / SHX / SHY / TAS (store register & (high byte + 1))
+        0x93 => sha(cpu, &AddressingMode::IndirectY),
+        0x9F => sha(cpu, &AddressingMode::AbsoluteY),
+        0x9E => shx(cpu),
+        0x9C => shy(cpu),
+        0x9B => tas(cpu),
+
+        // Multi-byte NOPs still fetch (and read) their operands
+        0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => { /* Do nothing */ }
+        0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => nop(cpu, &AddressingMode::Immediate),
+        0x04 | 0x44 | 0x64 => nop(cpu, &AddressingMode::ZeroPage),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => nop(cpu, &AddressingMode::ZeroPageX),
+        0x0C => nop(cpu, &AddressingMode::Absolute),
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => nop(cpu, &AddressingMode::AbsoluteX),
+
+        // JAM (KIL) locks up the CPU until reset
+        0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => jam(cpu),
     }
 }
 
-fn load(cpu: &mut CPU, mode: &AddressingMode) -> u8 {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
+// Opcodes the 65C02 added or redefined. Returns false for the ones it shares with the NMOS 6502.
+fn execute_65c02<B: Memory>(cpu: &mut CPU<B>, opcode: u8) -> bool {
+    match opcode {
+        // (zp) addressing
+        0x12 => ora(cpu, &AddressingMode::ZeroPageIndirect),
+        0x32 => and(cpu, &AddressingMode::ZeroPageIndirect),
+        0x52 => eor(cpu, &AddressingMode::ZeroPageIndirect),
+        0x72 => adc(cpu, &AddressingMode::ZeroPageIndirect),
+        0x92 => sta(cpu, &AddressingMode::ZeroPageIndirect),
+        0xB2 => lda(cpu, &AddressingMode::ZeroPageIndirect),
+        0xD2 => cmp(cpu, &AddressingMode::ZeroPageIndirect),
+        0xF2 => sbc(cpu, &AddressingMode::ZeroPageIndirect),
+
+        // BIT
+        0x89 => bit(cpu, &AddressingMode::Immediate),
+        0x34 => bit(cpu, &AddressingMode::ZeroPageX),
+        0x3C => bit(cpu, &AddressingMode::AbsoluteX),
+
+        // TSB / TRB
+        0x04 => tsb(cpu, &AddressingMode::ZeroPage),
+        0x0C => tsb(cpu, &AddressingMode::Absolute),
+        0x14 => trb(cpu, &AddressingMode::ZeroPage),
+        0x1C => trb(cpu, &AddressingMode::Absolute),
+
+        // STZ
+        0x64 => stz(cpu, &AddressingMode::ZeroPage),
+        0x74 => stz(cpu, &AddressingMode::ZeroPageX),
+        0x9C => stz(cpu, &AddressingMode::Absolute),
+        0x9E => stz(cpu, &AddressingMode::AbsoluteX),
+
+        // INC A / DEC A
+        0x1A => modify_accumulator(cpu, increment),
+        0x3A => modify_accumulator(cpu, decrement),
+
+        // PHX / PHY / PLX / PLY
+        0xDA => push_register(cpu, cpu.index_x(), !cpu.index_8bit()),
+        0x5A => push_register(cpu, cpu.index_y(), !cpu.index_8bit()),
+        0xFA => {
+            let value = pull_register(cpu, !cpu.index_8bit());
+            set_index_x(cpu, value);
+        }
+        0x7A => {
+            let value = pull_register(cpu, !cpu.index_8bit());
+            set_index_y(cpu, value);
+        }
+
+        // BRA
+        0x80 => branch(cpu, true),
+
+        // JMP (abs,X)
+        0x7C => jmp(cpu, &AddressingMode::AbsoluteIndexedIndirect),
+
+        // RMB0-7 / SMB0-7
+        _ if opcode & 0x0F == 0x07 => reset_or_set_memory_bit(cpu, opcode),
+
+        // BBR0-7 / BBS0-7
+        _ if opcode & 0x0F == 0x0F => branch_on_bit(cpu, opcode),
+
+        // WAI / STP
+        0xCB => cpu.state = CpuState::Waiting,
+        0xDB => cpu.state = CpuState::Stopped,
+
+        // Undefined opcodes are NOPs that still consume their operands
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => nop(cpu, &AddressingMode::Immediate),
+        0x44 => nop(cpu, &AddressingMode::ZeroPage),
+        0x54 | 0xD4 | 0xF4 => nop(cpu, &AddressingMode::ZeroPageX),
+        0x5C | 0xDC | 0xFC => nop(cpu, &AddressingMode::Absolute),
+        _ if opcode & 0x03 == 0x03 => { /* 1-byte, 1-cycle NOP */ }
+
+        _ => return false,
+    }
+    true
+}
+
+pub(crate) fn set_accumulator<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) {
+    cpu.register_a = value as u8;
+    // An 8-bit write leaves the hidden B byte alone
+    if wide {
+        cpu.register_b = (value >> 8) as u8;
+    }
+}
+
+pub(crate) fn set_index_x<B: Memory>(cpu: &mut CPU<B>, value: u16) {
+    let wide = !cpu.index_8bit();
+    cpu.register_x = value as u8;
+    cpu.register_x_high = if wide { (value >> 8) as u8 } else { 0 };
+    update_zero_and_negative_flags(cpu, value, wide);
+}
+
+pub(crate) fn set_index_y<B: Memory>(cpu: &mut CPU<B>, value: u16) {
+    let wide = !cpu.index_8bit();
+    cpu.register_y = value as u8;
+    cpu.register_y_high = if wide { (value >> 8) as u8 } else { 0 };
+    update_zero_and_negative_flags(cpu, value, wide);
+}
+
+// TXS / TCS: only the low byte moves in emulation mode
+pub(crate) fn set_stack<B: Memory>(cpu: &mut CPU<B>, value: u16) {
+    cpu.stack_pointer = value as u8;
+    if !cpu.emulation {
+        cpu.stack_pointer_high = (value >> 8) as u8;
+    }
+}
+
+fn transfer_to_accumulator<B: Memory>(cpu: &mut CPU<B>, value: u16) {
+    let wide = !cpu.memory_8bit();
+    set_accumulator(cpu, value, wide);
+    update_zero_and_negative_flags(cpu, value, wide);
+}
+
+// Writing P on the 65C816 in native mode can change register widths, narrowing the index
+// registers clears their high bytes. With E set, M and X read back as 1 and B as 0.
+pub(crate) fn set_status<B: Memory>(cpu: &mut CPU<B>, value: u8) {
+    cpu.status = value;
+    if cpu.emulation {
+        cpu.status |= FLAG_UNUSED;
+        cpu.status &= !FLAG_BREAK;
+    }
+    if cpu.index_8bit() {
+        cpu.register_x_high = 0;
+        cpu.register_y_high = 0;
+    }
+}
+
+fn sign_bit(wide: bool) -> u16 {
+    if wide { 0x8000 } else { 0x0080 }
+}
+
+fn width_mask(wide: bool) -> u16 {
+    if wide { 0xFFFF } else { 0x00FF }
+}
+
+// 16-bit addresses on the 8-bit chips, bank and all on the 65C816
+fn operand_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> (u32, bool) {
+    if cpu.variant == Variant::W65C816 {
+        get_long_operand_address(cpu, mode)
+    } else {
+        let (addr, page_crossed) = get_operand_address(cpu, mode);
+        (addr as u32, page_crossed)
+    }
+}
+
+fn write_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> u32 {
+    if cpu.variant == Variant::W65C816 {
+        get_long_write_address(cpu, mode)
+    } else {
+        get_write_address(cpu, mode).0 as u32
+    }
+}
+
+// The high byte of a 16-bit operand. Direct page and stack relative operands wrap inside
+// bank 0, everything else carries into the next bank.
+fn next_address(mode: &AddressingMode, addr: u32) -> u32 {
+    match mode {
+        AddressingMode::ZeroPage | AddressingMode::ZeroPageX | AddressingMode::ZeroPageY |
+        AddressingMode::StackRelative => (addr + 1) & 0xFFFF,
+        _ => (addr + 1) & 0xFF_FFFF,
+    }
+}
+
+fn read_value<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, addr: u32, wide: bool) -> u16 {
+    let lo = cpu.read_long(addr) as u16;
+    if !wide {
+        return lo;
+    }
+    let hi = cpu.read_long(next_address(mode, addr)) as u16;
+    (hi << 8) | lo
+}
+
+fn write_value<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, addr: u32, value: u16, wide: bool) {
+    cpu.write_long(addr, value as u8);
+    if wide {
+        cpu.write_long(next_address(mode, addr), (value >> 8) as u8);
+    }
+}
+
+// Fetches an 8 or 16-bit operand for a read instruction, adding the cycles for the
+// second byte and for indexing across a page
+pub(crate) fn read_operand<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, wide: bool) -> u16 {
+    if wide { cpu.cycles += 1; }
+
+    if let AddressingMode::Immediate = mode {
+        let lo = cpu.get_operand() as u16;
+        let hi = if wide { cpu.get_operand() as u16 } else { 0 };
+        return (hi << 8) | lo;
+    }
+
+    let (addr, page_crossed) = operand_address(cpu, mode);
     if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
-    update_zero_and_negative_flags(cpu, value);
+    read_value(cpu, mode, addr, wide)
+}
+
+fn load<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, wide: bool) -> u16 {
+    let value = read_operand(cpu, mode, wide);
+    update_zero_and_negative_flags(cpu, value, wide);
     value
 }
 
-fn store(cpu: &mut CPU, mode: &AddressingMode, value: u8) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    cpu.bus.write(addr, value);
+pub(crate) fn lda<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let value = load(cpu, mode, wide);
+    set_accumulator(cpu, value, wide);
 }
 
-fn compare(cpu: &mut CPU, mode: &AddressingMode, compare_with: u8) {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
-    if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
+fn ldx<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let value = read_operand(cpu, mode, !cpu.index_8bit());
+    set_index_x(cpu, value);
+}
+
+fn ldy<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let value = read_operand(cpu, mode, !cpu.index_8bit());
+    set_index_y(cpu, value);
+}
+
+fn store<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, value: u16, wide: bool) {
+    if wide { cpu.cycles += 1; }
+    let addr = write_address(cpu, mode);
+    write_value(cpu, mode, addr, value, wide);
+}
+
+pub(crate) fn sta<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    store(cpu, mode, cpu.accumulator(), !cpu.memory_8bit());
+}
+
+fn stx<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    store(cpu, mode, cpu.index_x(), !cpu.index_8bit());
+}
+
+fn sty<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    store(cpu, mode, cpu.index_y(), !cpu.index_8bit());
+}
+
+fn stz<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    store(cpu, mode, 0, !cpu.memory_8bit());
+}
+
+pub(crate) fn cmp<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    compare(cpu, mode, cpu.accumulator() & width_mask(wide), wide);
+}
+
+fn compare_index<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, register: u16) {
+    compare(cpu, mode, register, !cpu.index_8bit());
+}
+
+fn compare<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, compare_with: u16, wide: bool) {
+    let value = read_operand(cpu, mode, wide);
 
     cpu.set_flag(FLAG_CARRY, compare_with >= value);
     let result = compare_with.wrapping_sub(value);
-    update_zero_and_negative_flags(cpu, result);
+    update_zero_and_negative_flags(cpu, result, wide);
 }
 
-fn jmp(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    cpu.program_counter = addr;
+fn jmp<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let (addr, _) = operand_address(cpu, mode);
+    cpu.program_counter = addr as u16;
 }
 
-fn branch(cpu: &mut CPU, condition: bool) {
+fn branch<B: Memory>(cpu: &mut CPU<B>, condition: bool) {
     let (jump_address, page_crossed) = get_operand_address(cpu, &AddressingMode::Relative);
 
+    // A taken branch reads the next opcode while adding the offset, and reads again
+    // from the uncorrected address when the offset carries into the high byte. The
+    // 65C816 only spends that cycle in emulation mode.
     if condition {
         cpu.cycles += 1;
-        if page_crossed {
+        cpu.read_program(cpu.program_counter);
+        if page_crossed && cpu.emulation {
             cpu.cycles += 1;
+            cpu.read_program((cpu.program_counter & 0xFF00) | (jump_address & 0x00FF));
+        } else if cpu.variant != Variant::W65C816 {
+            // The extra cycle is not polled for interrupts, anything arriving on it waits
+            // until after the next instruction
+            cpu.poll = Poll::Early;
         }
         cpu.program_counter = jump_address;
     }
 }
 
-fn inc(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let mut value = cpu.bus.read(addr);
-    value = value.wrapping_add(1);
-    cpu.bus.write(addr, value);
-    update_zero_and_negative_flags(cpu, value);
+fn inc<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, increment);
 }
 
-fn dec(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let mut value = cpu.bus.read(addr);
-    value = value.wrapping_sub(1);
-    cpu.bus.write(addr, value);
-    update_zero_and_negative_flags(cpu, value);
+fn dec<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, decrement);
 }
 
-fn and(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
-    if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
-    cpu.register_a &= value;
-    update_zero_and_negative_flags(cpu, cpu.register_a);
+fn increment<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) -> u16 {
+    let result = value.wrapping_add(1) & width_mask(wide);
+    update_zero_and_negative_flags(cpu, result, wide);
+    result
 }
 
-fn ora(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
-    if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
-    cpu.register_a |= value;
-    update_zero_and_negative_flags(cpu, cpu.register_a);
+fn decrement<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) -> u16 {
+    let result = value.wrapping_sub(1) & width_mask(wide);
+    update_zero_and_negative_flags(cpu, result, wide);
+    result
 }
 
-fn eor(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
-    if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
-    cpu.register_a ^= value;
-    update_zero_and_negative_flags(cpu, cpu.register_a);
+type Operation<B> = fn(&mut CPU<B>, u16, bool) -> u16;
+
+// The NMOS ALU needs a cycle to work on the value, during which the unmodified value is
+// written back. I/O registers see both writes. The 65C02 reads the location twice instead,
+// and so does the 65C816 in native mode. A 16-bit result is written high byte first.
+fn read_modify_write<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, addr: u32, operation: Operation<B>) -> u16 {
+    let wide = !cpu.memory_8bit();
+    if wide { cpu.cycles += 2; }
+    let value = read_value(cpu, mode, addr, wide);
+    if cpu.variant == Variant::Wdc65C02 || !cpu.emulation {
+        cpu.read_long(if wide { next_address(mode, addr) } else { addr });
+    } else {
+        cpu.write_long(addr, value as u8);
+    }
+    let result = operation(cpu, value, wide);
+    if wide {
+        cpu.write_long(next_address(mode, addr), (result >> 8) as u8);
+    }
+    cpu.write_long(addr, result as u8);
+    result
 }
 
-fn tax(cpu: &mut CPU) {
-    cpu.register_x = cpu.register_a;
-    update_zero_and_negative_flags(cpu, cpu.register_x);
+fn modify_accumulator<B: Memory>(cpu: &mut CPU<B>, operation: Operation<B>) {
+    let wide = !cpu.memory_8bit();
+    let value = cpu.accumulator() & width_mask(wide);
+    let result = operation(cpu, value, wide);
+    set_accumulator(cpu, result, wide);
 }
 
-fn tay(cpu: &mut CPU) {
-    cpu.register_y = cpu.register_a;
-    update_zero_and_negative_flags(cpu, cpu.register_y);
+pub(crate) fn and<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let result = cpu.accumulator() & read_operand(cpu, mode, wide);
+    set_accumulator(cpu, result, wide);
+    update_zero_and_negative_flags(cpu, result, wide);
 }
 
-fn txa(cpu: &mut CPU) {
-    cpu.register_a = cpu.register_x;
-    update_zero_and_negative_flags(cpu, cpu.register_a);
+pub(crate) fn ora<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let result = cpu.accumulator() | read_operand(cpu, mode, wide);
+    set_accumulator(cpu, result, wide);
+    update_zero_and_negative_flags(cpu, result, wide);
 }
 
-fn tya(cpu: &mut CPU) {
-    cpu.register_a = cpu.register_y;
-    update_zero_and_negative_flags(cpu, cpu.register_a);
+pub(crate) fn eor<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let result = cpu.accumulator() ^ read_operand(cpu, mode, wide);
+    set_accumulator(cpu, result, wide);
+    update_zero_and_negative_flags(cpu, result, wide);
 }
 
-fn txs(cpu: &mut CPU) {
+// Stack values go high byte first so they end up little-endian in memory
+pub(crate) fn push_value<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) {
+    if wide {
+        cpu.push_stack((value >> 8) as u8);
+    }
+    cpu.push_stack(value as u8);
+}
 
-    cpu.stack_pointer = cpu.register_x;
+pub(crate) fn pull_value<B: Memory>(cpu: &mut CPU<B>, wide: bool) -> u16 {
+    let lo = cpu.pop_stack() as u16;
+    let hi = if wide { cpu.pop_stack() as u16 } else { 0 };
+    (hi << 8) | lo
 }
 
-fn tsx(cpu: &mut CPU) {
-    cpu.register_x = cpu.stack_pointer;
-    update_zero_and_negative_flags(cpu, cpu.register_x);
+// Pushes and pulls of A, X and Y take a cycle more when the register is 16 bits wide
+fn push_register<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) {
+    if wide {
+        cpu.cycles += 1;
+    }
+    push_value(cpu, value, wide);
 }
 
-fn inx(cpu: &mut CPU) {
-    cpu.register_x = cpu.register_x.wrapping_add(1);
-    update_zero_and_negative_flags(cpu, cpu.register_x);
+fn pull_register<B: Memory>(cpu: &mut CPU<B>, wide: bool) -> u16 {
+    stack_dummy_read(cpu);
+    if wide {
+        cpu.cycles += 1;
+    }
+    pull_value(cpu, wide)
 }
 
-fn iny(cpu: &mut CPU) {
-    cpu.register_y = cpu.register_y.wrapping_add(1);
-    update_zero_and_negative_flags(cpu, cpu.register_y);
+// The 65C816 in native mode pushes P as it is, bit 4 is the X flag there
+fn php<B: Memory>(cpu: &mut CPU<B>) {
+    let mut flags = cpu.status;
+    if cpu.emulation {
+        flags |= FLAG_UNUSED;
+        flags |= FLAG_BREAK;
+    }
+    cpu.push_stack(flags);
 }
 
-fn dex(cpu: &mut CPU) {
-    cpu.register_x = cpu.register_x.wrapping_sub(1);
-    update_zero_and_negative_flags(cpu, cpu.register_x);
+// Pulls spend a cycle reading the stack before S is incremented
+pub(crate) fn stack_dummy_read<B: Memory>(cpu: &mut CPU<B>) {
+    cpu.read(cpu.stack_address());
 }
 
-fn dey(cpu: &mut CPU) {
-    cpu.register_y = cpu.register_y.wrapping_sub(1);
-    update_zero_and_negative_flags(cpu, cpu.register_y);
+fn pla<B: Memory>(cpu: &mut CPU<B>) {
+    let wide = !cpu.memory_8bit();
+    let value = pull_register(cpu, wide);
+    set_accumulator(cpu, value, wide);
+    update_zero_and_negative_flags(cpu, value, wide);
 }
 
-fn pha(cpu: &mut CPU) {
-    cpu.push_stack(cpu.register_a);
+fn plp<B: Memory>(cpu: &mut CPU<B>) {
+    stack_dummy_read(cpu);
+    let status = cpu.pop_stack();
+    set_status(cpu, status);
 }
 
-fn php(cpu: &mut CPU) {
-    let mut flags = cpu.status;
-    flags |= FLAG_UNUSED;
-    flags |= FLAG_BREAK;
-    cpu.push_stack(flags);
+// BIT #imm only affects Z, there are no memory bits to copy into N and V
+fn bit<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let value = read_operand(cpu, mode, wide);
+    cpu.set_flag(FLAG_ZERO, cpu.accumulator() & value & width_mask(wide) == 0);
+
+    if let AddressingMode::Immediate = mode {
+        return;
+    }
+    let sign = sign_bit(wide);
+    cpu.set_flag(FLAG_NEGATIVE, value & sign != 0);
+    cpu.set_flag(FLAG_OVERFLOW, value & (sign >> 1) != 0);
 }
 
-fn pla(cpu: &mut CPU) {
-    cpu.register_a = cpu.pop_stack();
-    update_zero_and_negative_flags(cpu, cpu.register_a);
+fn tsb<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let (addr, _) = operand_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, |cpu, value, wide| {
+        let a = cpu.accumulator() & width_mask(wide);
+        cpu.set_flag(FLAG_ZERO, a & value == 0);
+        value | a
+    });
 }
 
-fn plp(cpu: &mut CPU) {
-    cpu.status = cpu.pop_stack();
-    cpu.status &= !FLAG_BREAK;
-    cpu.status |= FLAG_UNUSED;
+fn trb<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let (addr, _) = operand_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, |cpu, value, wide| {
+        let a = cpu.accumulator() & width_mask(wide);
+        cpu.set_flag(FLAG_ZERO, a & value == 0);
+        value & !a
+    });
 }
 
-fn bit(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let value = cpu.bus.read(addr);
+// Bits 4-6 of the opcode select the bit, bit 7 selects reset (RMB) or set (SMB)
+fn reset_or_set_memory_bit<B: Memory>(cpu: &mut CPU<B>, opcode: u8) {
+    let (addr, _) = get_operand_address(cpu, &AddressingMode::ZeroPage);
+    let mask = 1 << ((opcode >> 4) & 0x07);
+    let value = cpu.read(addr);
+    cpu.read(addr);
+    let result = if opcode & 0x80 != 0 { value | mask } else { value & !mask };
+    cpu.write(addr, result);
+}
 
-    cpu.set_flag(FLAG_ZERO, (cpu.register_a & value) == 0);
-    cpu.set_flag(FLAG_NEGATIVE, value & FLAG_NEGATIVE > 0);
-    cpu.set_flag(FLAG_OVERFLOW, value & FLAG_OVERFLOW > 0);
+// Same encoding as RMB/SMB: BBR branches on a clear bit, BBS on a set bit
+fn branch_on_bit<B: Memory>(cpu: &mut CPU<B>, opcode: u8) {
+    let (addr, _) = get_operand_address(cpu, &AddressingMode::ZeroPage);
+    let mask = 1 << ((opcode >> 4) & 0x07);
+    let bit_set = cpu.read(addr) & mask != 0;
+    cpu.read(addr);
+    branch(cpu, bit_set == (opcode & 0x80 != 0));
 }
 
-fn asl(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let value = cpu.bus.read(addr);
-    let result = shift_left(cpu, value);
-    cpu.bus.write(addr, result);
+fn asl<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = shift_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, shift_left);
 }
 
-fn lsr(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let value = cpu.bus.read(addr);
-    let result = shift_right(cpu, value);
-    cpu.bus.write(addr, result);
+fn lsr<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = shift_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, shift_right);
 }
 
-fn rol(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let value = cpu.bus.read(addr);
-    let result = rotate_left(cpu, value);
-    cpu.bus.write(addr, result);
+fn rol<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = shift_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, rotate_left);
 }
 
-fn ror(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, _) = get_operand_address(cpu, mode);
-    let value = cpu.bus.read(addr);
-    let result = rotate_right(cpu, value);
-    cpu.bus.write(addr, result);
+fn ror<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = shift_address(cpu, mode);
+    read_modify_write(cpu, mode, addr, rotate_right);
 }
 
-fn jsr(cpu: &mut CPU) {
-    let target_addr = cpu.fetch_u16();
-    let return_addr = cpu.program_counter - 1;
+// The 65C02 only spends the extra indexing cycle on shifts and rotates when a page is crossed
+fn shift_address<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) -> u32 {
+    if cpu.variant == Variant::Wdc65C02 {
+        let (addr, page_crossed) = get_operand_address(cpu, mode);
+        if page_crossed { cpu.cycles += 1; }
+        addr as u32
+    } else {
+        write_address(cpu, mode)
+    }
+}
+
+// JSR pushes the return address before it has fetched the high byte of the target
+fn jsr<B: Memory>(cpu: &mut CPU<B>) {
+    let lo = cpu.get_operand() as u16;
+    stack_dummy_read(cpu);
+
+    let return_addr = cpu.program_counter;
     cpu.push_stack(((return_addr >> 8) & 0xFF) as u8);
     cpu.push_stack((return_addr & 0xFF) as u8);
-    cpu.program_counter = target_addr;
+
+    let hi = cpu.get_operand() as u16;
+    cpu.program_counter = (hi << 8) | lo;
 }
 
-fn rts(cpu: &mut CPU) {
+fn rts<B: Memory>(cpu: &mut CPU<B>) {
+    stack_dummy_read(cpu);
     let lo = cpu.pop_stack() as u16;
     let hi = cpu.pop_stack() as u16;
-    cpu.program_counter = (hi << 8 | lo) + 1;
+    let return_addr = hi << 8 | lo;
+
+    // One more read while the PC is incremented past the JSR
+    cpu.read_program(return_addr);
+    cpu.program_counter = return_addr.wrapping_add(1);
 }
 
-fn rti(cpu: &mut CPU) {
-    cpu.status = cpu.pop_stack();
-    cpu.status &= !FLAG_BREAK;
-    cpu.status |= FLAG_UNUSED;
+// Native mode also pulls the program bank
+fn rti<B: Memory>(cpu: &mut CPU<B>) {
+    stack_dummy_read(cpu);
+    let status = cpu.pop_stack();
+    set_status(cpu, status);
 
     let lo = cpu.pop_stack() as u16;
     let hi = cpu.pop_stack() as u16;
     cpu.program_counter = (hi << 8) | lo;
+
+    if !cpu.emulation {
+        cpu.cycles += 1;
+        cpu.program_bank = cpu.pop_stack();
+    }
 }
 
-fn shift_left(cpu: &mut CPU, value: u8) -> u8 {
-    cpu.set_flag(FLAG_CARRY, value & 0x80 != 0);
-    let result = value << 1;
-    update_zero_and_negative_flags(cpu, result);
+fn shift_left<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) -> u16 {
+    cpu.set_flag(FLAG_CARRY, value & sign_bit(wide) != 0);
+    let result = (value << 1) & width_mask(wide);
+    update_zero_and_negative_flags(cpu, result, wide);
     result
 }
 
-fn shift_right(cpu: &mut CPU, value: u8) -> u8 {
-    cpu.set_flag(FLAG_CARRY, value & 0x01 != 0);
+fn shift_right<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) -> u16 {
+    cpu.set_flag(FLAG_CARRY, value & 0x0001 != 0);
     let result = value >> 1;
-    update_zero_and_negative_flags(cpu, result);
+    update_zero_and_negative_flags(cpu, result, wide);
     result
 }
 
-fn rotate_left(cpu: &mut CPU, value: u8) -> u8 {
-    let old_carry = cpu.has_flag(FLAG_CARRY);
-    cpu.set_flag(FLAG_CARRY, value & 0x80 != 0);
-    let mut result = value << 1;
-
-    if old_carry {
-        result |= 0x01;
-    }
-    update_zero_and_negative_flags(cpu, result);
+fn rotate_left<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) -> u16 {
+    let old_carry = cpu.has_flag(FLAG_CARRY) as u16;
+    cpu.set_flag(FLAG_CARRY, value & sign_bit(wide) != 0);
+    let result = ((value << 1) | old_carry) & width_mask(wide);
+    update_zero_and_negative_flags(cpu, result, wide);
     result
 }
 
-fn rotate_right(cpu: &mut CPU, value: u8) -> u8 {
-    let old_carry = cpu.has_flag(FLAG_CARRY);
-    cpu.set_flag(FLAG_CARRY, value & 0x01 != 0);
-    let mut result = value >> 1;
-
-    if old_carry {
-        result |= 0x80;
-    }
-    update_zero_and_negative_flags(cpu, result);
+fn rotate_right<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool) -> u16 {
+    let old_carry = if cpu.has_flag(FLAG_CARRY) { sign_bit(wide) } else { 0 };
+    cpu.set_flag(FLAG_CARRY, value & 0x0001 != 0);
+    let result = (value >> 1) | old_carry;
+    update_zero_and_negative_flags(cpu, result, wide);
     result
 }
 
-fn update_zero_and_negative_flags(cpu: &mut CPU, result: u8) {
-    cpu.set_flag(FLAG_ZERO, result == 0);
-    cpu.set_flag(FLAG_NEGATIVE, result & 0b1000_0000 != 0);
+pub(crate) fn update_zero_and_negative_flags<B: Memory>(cpu: &mut CPU<B>, result: u16, wide: bool) {
+    cpu.set_flag(FLAG_ZERO, result & width_mask(wide) == 0);
+    cpu.set_flag(FLAG_NEGATIVE, result & sign_bit(wide) != 0);
 }
 
-fn sec(cpu: &mut CPU) {
+fn sec<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status |= FLAG_CARRY;
 }
 
-fn sed(cpu: &mut CPU) {
+fn sed<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status |= FLAG_DECIMAL;
 }
 
-fn sei(cpu: &mut CPU) {
+fn sei<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status |= FLAG_INTERRUPT;
 }
 
-fn clc(cpu: &mut CPU) {
+fn clc<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status &= !FLAG_CARRY;
 }
 
-fn cld(cpu: &mut CPU) {
+fn cld<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status &= !FLAG_DECIMAL;
 }
 
-fn cli(cpu: &mut CPU) {
+fn cli<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status &= !FLAG_INTERRUPT;
 }
 
-fn clv(cpu: &mut CPU) {
+fn clv<B: Memory>(cpu: &mut CPU<B>) {
     cpu.status &= !FLAG_OVERFLOW;
 }
 
-fn brk(cpu: &mut CPU) {
-    // BRK pushes PC + 1, not PC + 2 like JSR
-    let return_addr = cpu.program_counter + 1;
-    cpu.push_stack(((return_addr >> 8) & 0xFF) as u8);
-    cpu.push_stack((return_addr & 0xFF) as u8);
-
-    let mut status = cpu.status;
-    status |= FLAG_UNUSED;
-    status |= FLAG_BREAK;
-    cpu.push_stack(status);
+fn brk<B: Memory>(cpu: &mut CPU<B>) {
+    // BRK reads and skips a padding byte, so it returns to PC + 2
+    cpu.interrupt_sequence(InterruptSource::Brk);
+}
 
-    cpu.status |= FLAG_INTERRUPT;
+// The 2A03 still lets SED/CLD toggle the D flag, but its ALU has no BCD logic
+fn decimal_mode<B: Memory>(cpu: &CPU<B>) -> bool {
+    cpu.has_flag(FLAG_DECIMAL) && cpu.variant != Variant::Ricoh2A03
+}
 
-    let lo = cpu.bus.read(0xFFFE) as u16;
-    let hi = cpu.bus.read(0xFFFF) as u16;
-    cpu.program_counter = (hi << 8) | lo;
+pub(crate) fn adc<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let value = read_operand(cpu, mode, wide);
+    if cpu.variant == Variant::W65C816 {
+        add_with_carry_wide(cpu, value, wide, false);
+    } else {
+        add_with_carry(cpu, value as u8);
+    }
 }
 
-fn adc(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
-    if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
-    
+fn add_with_carry<B: Memory>(cpu: &mut CPU<B>, value: u8) {
     let a = cpu.register_a;
     let carry = if cpu.has_flag(FLAG_CARRY) { 1 } else { 0 };
 
-    if cpu.has_flag(FLAG_DECIMAL) {
+    if decimal_mode(cpu) && cpu.variant == Variant::Wdc65C02 {
+        // The 65C02 spends an extra cycle to produce valid N and Z flags
+        cpu.cycles += 1;
+
+        let mut lo = (a & 0x0F) as u16 + (value & 0x0F) as u16 + carry as u16;
+        if lo > 0x09 { lo = ((lo + 0x06) & 0x0F) + 0x10; }
+
+        // V still comes from the signed intermediate result, like the NMOS part
+        let signed = (a & 0xF0) as i8 as i16 + (value & 0xF0) as i8 as i16 + lo as i16;
+        cpu.set_flag(FLAG_OVERFLOW, !(-128..=127).contains(&signed));
+
+        let mut sum = (a & 0xF0) as u16 + (value & 0xF0) as u16 + lo;
+        if sum > 0x9F { sum += 0x60; }
+        cpu.set_flag(FLAG_CARRY, sum > 0xFF);
+
+        cpu.register_a = sum as u8;
+        update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+    } else if decimal_mode(cpu) {
         let mut lo = (a & 0x0F) + (value & 0x0F) + carry;
         let mut hi = (a >> 4) + (value >> 4) + if lo > 0x09 { 1 } else { 0 };
 
@@ -535,7 +977,7 @@ fn adc(cpu: &mut CPU, mode: &AddressingMode) {
         cpu.set_flag(FLAG_CARRY, hi > 0x0F);
 
         cpu.register_a = result;
-        update_zero_and_negative_flags(cpu, bin_sum as u8);
+        update_zero_and_negative_flags(cpu, bin_sum as u8 as u16, false);
     } else {
         let sum = (a as u16) + (value as u16) + carry as u16;
         cpu.set_flag(FLAG_CARRY, sum > 0xFF);
@@ -544,19 +986,42 @@ fn adc(cpu: &mut CPU, mode: &AddressingMode) {
         cpu.set_flag(FLAG_OVERFLOW, (value ^ result) & (a ^ result) & 0x80 != 0);
 
         cpu.register_a = result;
-        update_zero_and_negative_flags(cpu, cpu.register_a);
+        update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
     }
 }
 
-fn sbc(cpu: &mut CPU, mode: &AddressingMode) {
-    let (addr, page_crossed) = get_operand_address(cpu, mode);
-    if page_crossed { cpu.cycles += 1; }
-    let value = cpu.bus.read(addr);
-    
+pub(crate) fn sbc<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let wide = !cpu.memory_8bit();
+    let value = read_operand(cpu, mode, wide);
+    if cpu.variant == Variant::W65C816 {
+        add_with_carry_wide(cpu, value, wide, true);
+    } else {
+        subtract_with_carry(cpu, value as u8);
+    }
+}
+
+fn subtract_with_carry<B: Memory>(cpu: &mut CPU<B>, value: u8) {
     let a = cpu.register_a;
     let carry = if cpu.has_flag(FLAG_CARRY) { 1 } else { 0 };
 
-    if cpu.has_flag(FLAG_DECIMAL) {
+    if decimal_mode(cpu) && cpu.variant == Variant::Wdc65C02 {
+        cpu.cycles += 1;
+
+        // C and V match binary subtraction, N and Z come from the decimal result
+        let inverted_val = value ^ 0xFF;
+        let bin_sum = (a as u16) + (inverted_val as u16) + carry as u16;
+        cpu.set_flag(FLAG_CARRY, bin_sum > 0xFF);
+        cpu.set_flag(FLAG_OVERFLOW, (inverted_val ^ bin_sum as u8) & (a ^ bin_sum as u8) & 0x80 != 0);
+
+        let borrow = 1 - carry as i16;
+        let lo = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+        let mut diff = a as i16 - value as i16 - borrow;
+        if diff < 0 { diff -= 0x60; }
+        if lo < 0 { diff -= 0x06; }
+
+        cpu.register_a = diff as u8;
+        update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+    } else if decimal_mode(cpu) {
         let bin_diff = (a as u16).wrapping_sub(value as u16).wrapping_sub(1 - carry as u16);
         let mut lo = (a & 0x0F).wrapping_sub(value & 0x0F).wrapping_sub(1 - carry);
         let mut hi = (a >> 4).wrapping_sub(value >> 4).wrapping_sub(if (lo as i8) < 0 { 1 } else { 0 });
@@ -569,7 +1034,7 @@ fn sbc(cpu: &mut CPU, mode: &AddressingMode) {
 
         let result = (hi << 4) | lo;
         cpu.register_a = result;
-        update_zero_and_negative_flags(cpu, bin_diff as u8);
+        update_zero_and_negative_flags(cpu, bin_diff as u8 as u16, false);
     } else {
         let inverted_val = value ^ 0xFF;
         let sum = (a as u16) + (inverted_val as u16) + carry as u16;
@@ -580,6 +1045,224 @@ fn sbc(cpu: &mut CPU, mode: &AddressingMode) {
         cpu.set_flag(FLAG_OVERFLOW, (inverted_val ^ result) & (a ^ result) & 0x80 != 0);
 
         cpu.register_a = result;
-        update_zero_and_negative_flags(cpu, cpu.register_a);
+        update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+    }
+}
+
+// The 65C816 ALU, for both widths. SBC adds the inverted operand, in decimal mode
+// every nibble is corrected on the way up (or down) like the real ALU does.
+// N, V and Z are valid in decimal mode, and no extra cycle is spent on it.
+fn add_with_carry_wide<B: Memory>(cpu: &mut CPU<B>, value: u16, wide: bool, subtract: bool) {
+    let mask = width_mask(wide) as i32;
+    let sign = sign_bit(wide) as i32;
+    let nibbles = if wide { 4 } else { 2 };
+
+    let a = cpu.accumulator() as i32 & mask;
+    let data = if subtract { !value as i32 & mask } else { value as i32 & mask };
+    let decimal = cpu.has_flag(FLAG_DECIMAL);
+    let mut carry = cpu.has_flag(FLAG_CARRY) as i32;
+
+    let mut result = a + data + carry;
+    if decimal {
+        result = 0;
+        for i in 0..nibbles {
+            let shift = 4 * i;
+            let nibble = 0xF << shift;
+            let below = (1 << shift) - 1;
+            result = (a & nibble) + (data & nibble) + (carry << shift) + (result & below);
+            if i == nibbles - 1 {
+                break;
+            }
+            result = decimal_adjust(result, shift, subtract);
+            carry = (result > (nibble | below)) as i32;
+        }
+    }
+
+    cpu.set_flag(FLAG_OVERFLOW, !(a ^ data) & (a ^ result) & sign != 0);
+
+    if decimal {
+        result = decimal_adjust(result, 4 * (nibbles - 1), subtract);
+    }
+    cpu.set_flag(FLAG_CARRY, result > mask);
+
+    let result = (result & mask) as u16;
+    set_accumulator(cpu, result, wide);
+    update_zero_and_negative_flags(cpu, result, wide);
+}
+
+fn decimal_adjust(result: i32, shift: i32, subtract: bool) -> i32 {
+    let below = (1 << shift) - 1;
+    if subtract {
+        if result <= ((0xF << shift) | below) { result - (0x6 << shift) } else { result }
+    } else if result > ((0x9 << shift) | below) {
+        result + (0x6 << shift)
+    } else {
+        result
     }
-}
\ No newline at end of file
+}
+
+// Magic constant for the unstable ANE/LXA opcodes. It varies between chips and even
+// with temperature, $EE is the value most commonly observed on real hardware.
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
+fn jam<B: Memory>(cpu: &mut CPU<B>) {
+    // Leave the PC on the JAM opcode so the crash site is easy to find
+    cpu.program_counter = cpu.program_counter.wrapping_sub(1);
+    cpu.state = CpuState::Jammed;
+}
+
+fn nop<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let (addr, page_crossed) = get_operand_address(cpu, mode);
+    if page_crossed { cpu.cycles += 1; }
+    cpu.read(addr);
+}
+
+fn slo<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    let result = read_modify_write(cpu, mode, addr, shift_left) as u8;
+    cpu.register_a |= result;
+    update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+}
+
+fn rla<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    let result = read_modify_write(cpu, mode, addr, rotate_left) as u8;
+    cpu.register_a &= result;
+    update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+}
+
+fn sre<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    let result = read_modify_write(cpu, mode, addr, shift_right) as u8;
+    cpu.register_a ^= result;
+    update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+}
+
+fn rra<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    let result = read_modify_write(cpu, mode, addr, rotate_right) as u8;
+    add_with_carry(cpu, result);
+}
+
+fn lax<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let value = load(cpu, mode, false) as u8;
+    cpu.register_a = value;
+    cpu.register_x = value;
+}
+
+fn dcp<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    let value = read_modify_write(cpu, mode, addr, |_, value, _| value.wrapping_sub(1) & 0xFF) as u8;
+
+    cpu.set_flag(FLAG_CARRY, cpu.register_a >= value);
+    update_zero_and_negative_flags(cpu, cpu.register_a.wrapping_sub(value) as u16, false);
+}
+
+fn isc<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    let addr = write_address(cpu, mode);
+    let value = read_modify_write(cpu, mode, addr, |_, value, _| value.wrapping_add(1) & 0xFF) as u8;
+    subtract_with_carry(cpu, value);
+}
+
+fn anc<B: Memory>(cpu: &mut CPU<B>) {
+    and(cpu, &AddressingMode::Immediate);
+    cpu.set_flag(FLAG_CARRY, cpu.has_flag(FLAG_NEGATIVE));
+}
+
+fn alr<B: Memory>(cpu: &mut CPU<B>) {
+    and(cpu, &AddressingMode::Immediate);
+    modify_accumulator(cpu, shift_right);
+}
+
+fn arr<B: Memory>(cpu: &mut CPU<B>) {
+    let value = cpu.get_operand();
+    let and_result = cpu.register_a & value;
+    let carry_in = if cpu.has_flag(FLAG_CARRY) { 0x80 } else { 0 };
+    let mut result = (and_result >> 1) | carry_in;
+
+    update_zero_and_negative_flags(cpu, result as u16, false);
+
+    if decimal_mode(cpu) {
+        // Decimal mode: V from bit 6 changing, then a BCD-style fixup on each nibble
+        cpu.set_flag(FLAG_OVERFLOW, (and_result ^ result) & 0x40 != 0);
+
+        let lo = and_result & 0x0F;
+        let hi = and_result >> 4;
+        if lo + (lo & 0x01) > 0x05 {
+            result = (result & 0xF0) | (result.wrapping_add(0x06) & 0x0F);
+        }
+        let carry = hi + (hi & 0x01) > 0x05;
+        if carry {
+            result = result.wrapping_add(0x60);
+        }
+        cpu.set_flag(FLAG_CARRY, carry);
+    } else {
+        cpu.set_flag(FLAG_CARRY, result & 0x40 != 0);
+        cpu.set_flag(FLAG_OVERFLOW, ((result >> 6) ^ (result >> 5)) & 0x01 != 0);
+    }
+
+    cpu.register_a = result;
+}
+
+fn ane<B: Memory>(cpu: &mut CPU<B>) {
+    let value = cpu.get_operand();
+    cpu.register_a = (cpu.register_a | UNSTABLE_MAGIC) & cpu.register_x & value;
+    update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+}
+
+fn lxa<B: Memory>(cpu: &mut CPU<B>) {
+    let value = cpu.get_operand();
+    cpu.register_a = (cpu.register_a | UNSTABLE_MAGIC) & value;
+    cpu.register_x = cpu.register_a;
+    update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+}
+
+fn sbx<B: Memory>(cpu: &mut CPU<B>) {
+    let value = cpu.get_operand();
+    let and_result = cpu.register_a & cpu.register_x;
+
+    cpu.set_flag(FLAG_CARRY, and_result >= value);
+    cpu.register_x = and_result.wrapping_sub(value);
+    update_zero_and_negative_flags(cpu, cpu.register_x as u16, false);
+}
+
+fn las<B: Memory>(cpu: &mut CPU<B>) {
+    let value = load(cpu, &AddressingMode::AbsoluteY, false) as u8 & cpu.stack_pointer;
+    cpu.register_a = value;
+    cpu.register_x = value;
+    cpu.stack_pointer = value;
+    update_zero_and_negative_flags(cpu, value as u16, false);
+}
+
+fn sha<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode) {
+    store_high_and(cpu, mode, cpu.register_a & cpu.register_x, cpu.register_y);
+}
+
+fn shx<B: Memory>(cpu: &mut CPU<B>) {
+    store_high_and(cpu, &AddressingMode::AbsoluteY, cpu.register_x, cpu.register_y);
+}
+
+fn shy<B: Memory>(cpu: &mut CPU<B>) {
+    store_high_and(cpu, &AddressingMode::AbsoluteX, cpu.register_y, cpu.register_x);
+}
+
+fn tas<B: Memory>(cpu: &mut CPU<B>) {
+    cpu.stack_pointer = cpu.register_a & cpu.register_x;
+    store_high_and(cpu, &AddressingMode::AbsoluteY, cpu.stack_pointer, cpu.register_y);
+}
+
+// Shared by SHA/SHX/SHY/TAS: the stored value is ANDed with the high byte of the
+// base address + 1, and when indexing crosses a page that value also replaces
+// the high byte of the target address.
+fn store_high_and<B: Memory>(cpu: &mut CPU<B>, mode: &AddressingMode, value: u8, index: u8) {
+    let (addr, page_crossed) = get_write_address(cpu, mode);
+    let base = addr.wrapping_sub(index as u16);
+    let result = value & ((base >> 8) as u8).wrapping_add(1);
+
+    let target = if page_crossed {
+        ((result as u16) << 8) | (addr & 0x00FF)
+    } else {
+        addr
+    };
+    cpu.write(target, result);
+}
@@ -0,0 +1,218 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use crate::bus::Memory;
+use crate::cpu::{CPU, Interrupt, Poll, Registers};
+use crate::snapshot::{MemorySnapshot, SnapshotError};
+
+type SaveState<B> = fn(&CPU<B>) -> Result<Vec<u8>, SnapshotError>;
+type LoadState<B> = fn(&mut CPU<B>, &[u8]) -> Result<(), SnapshotError>;
+type RestoreWrite<B> = fn(&mut B, u32, u8);
+
+// History for stepping backwards. Every `interval` cycles a full save state is kept as a
+// keyframe, and in between only the registers at each instruction boundary and the bytes
+// the CPU wrote. Going back loads the keyframe before the target and writes the logged
+// bytes back into the backing store, so nothing has to be executed again and no device
+// sees the writes a second time.
+pub struct Rewind<B: Memory> {
+    interval: u64,                 // Cycles between keyframes
+    limit: usize,                  // Keyframes kept, older history is dropped
+    keyframes: VecDeque<Keyframe>,
+    save: SaveState<B>,
+    load: LoadState<B>,
+    restore_write: RestoreWrite<B>,
+}
+
+struct Keyframe {
+    cycle: u64,
+    snapshot: Vec<u8>,
+    boundaries: Vec<Boundary>, // After each instruction since the snapshot
+    writes: Vec<(u32, u8)>,    // Every byte the CPU wrote since the snapshot, in order
+}
+
+// What the CPU looked like after an instruction, besides memory
+#[derive(Clone, Copy)]
+struct Boundary {
+    registers: Registers,
+    irq_line: u32,
+    nmi_line: bool,
+    nmi_pending: bool,
+    pending_interrupt: Option<Interrupt>,
+    so_line: bool,
+    so_pending: bool,
+    poll_mask: bool,
+    poll: Poll,
+    nmi_hijacked: bool,
+    rdy: bool,
+    stall_cycles: u64,
+    stall_align: bool,
+    cycle_debt: u64,
+    writes: usize, // Length of the write log at this point
+}
+
+impl<B: Memory + MemorySnapshot> CPU<B> {
+    // Starts keeping history from here on. Costs a save state per keyframe, plus a few
+    // dozen bytes per instruction and a few per byte written in between.
+    pub fn enable_rewind(&mut self, interval: u64, keyframes: usize) {
+        self.rewind = Some(Box::new(Rewind {
+            interval: interval.max(1),
+            limit: keyframes.max(1),
+            keyframes: VecDeque::new(),
+            save: CPU::save_state,
+            load: CPU::load_state,
+            restore_write: B::restore_write,
+        }));
+        self.restart_rewind();
+    }
+}
+
+impl<B: Memory> CPU<B> {
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    // Earliest cycle count history still reaches back to
+    pub fn rewind_start(&self) -> Option<u64> {
+        self.rewind.as_ref()?.keyframes.front().map(|keyframe| keyframe.cycle)
+    }
+
+    // Undoes the last instruction (or interrupt entry). False when there is no history left.
+    pub fn step_back(&mut self) -> bool {
+        let Some(mut rewind) = self.rewind.take() else {
+            return false;
+        };
+
+        // A keyframe with nothing after it is the same point as the end of the one before
+        while rewind.keyframes.len() > 1
+            && rewind.keyframes.back().is_some_and(|keyframe| keyframe.boundaries.is_empty())
+        {
+            rewind.keyframes.pop_back();
+        }
+
+        let done = match rewind.keyframes.back() {
+            Some(keyframe) if !keyframe.boundaries.is_empty() => {
+                let last = rewind.keyframes.len() - 1;
+                let boundary = keyframe.boundaries.len() - 1;
+                self.restore(&mut rewind, last, boundary)
+            }
+            _ => false,
+        };
+        self.rewind = Some(rewind);
+        done
+    }
+
+    // Goes back to the last instruction boundary at or before `cycle`. False if that is
+    // further back than history reaches.
+    pub fn rewind_to(&mut self, cycle: u64) -> bool {
+        let Some(mut rewind) = self.rewind.take() else {
+            return false;
+        };
+
+        let target = rewind.keyframes.iter().rposition(|keyframe| keyframe.cycle <= cycle).map(|index| {
+            let boundaries = &rewind.keyframes[index].boundaries;
+            (index, boundaries.partition_point(|boundary| boundary.registers.cycles <= cycle))
+        });
+        let done = match target {
+            Some((index, boundary)) => self.restore(&mut rewind, index, boundary),
+            None => false,
+        };
+        self.rewind = Some(rewind);
+        done
+    }
+
+    // Puts the CPU and memory back to keyframe `index` plus its first `boundaries`
+    // instructions, and forgets everything after that
+    fn restore(&mut self, rewind: &mut Rewind<B>, index: usize, boundaries: usize) -> bool {
+        let keyframe = &mut rewind.keyframes[index];
+        if (rewind.load)(self, &keyframe.snapshot).is_err() {
+            return false;
+        }
+
+        keyframe.boundaries.truncate(boundaries);
+        let boundary = keyframe.boundaries.last().copied();
+        keyframe.writes.truncate(boundary.map_or(0, |boundary| boundary.writes));
+        for &(address, data) in &keyframe.writes {
+            (rewind.restore_write)(&mut self.bus, address, data);
+        }
+        if let Some(boundary) = boundary {
+            self.set_registers(boundary.registers);
+            self.irq_line = boundary.irq_line;
+            self.nmi_line = boundary.nmi_line;
+            self.nmi_pending = boundary.nmi_pending;
+            self.pending_interrupt = boundary.pending_interrupt;
+            self.so_line = boundary.so_line;
+            self.so_pending = boundary.so_pending;
+            self.poll_mask = boundary.poll_mask;
+            self.poll = boundary.poll;
+            self.nmi_hijacked = boundary.nmi_hijacked;
+            self.rdy = boundary.rdy;
+            self.stall_cycles = boundary.stall_cycles;
+            self.stall_align = boundary.stall_align;
+            self.cycle_debt = boundary.cycle_debt;
+        }
+        rewind.keyframes.truncate(index + 1);
+        self.forget_writes_from(self.cycles);
+        true
+    }
+
+    // Throws the history away and starts over with a keyframe of the current state
+    pub(crate) fn restart_rewind(&mut self) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.keyframes.clear();
+            self.record_boundary();
+        }
+    }
+
+    // A byte the CPU wrote to the bus
+    pub(crate) fn log_write(&mut self, address: u32, data: u8) {
+        if let Some(keyframe) = self.rewind.as_mut().and_then(|rewind| rewind.keyframes.back_mut()) {
+            keyframe.writes.push((address, data));
+        }
+    }
+
+    // Called at every instruction boundary: notes where the CPU is, and takes a keyframe
+    // once `interval` cycles have gone by since the last one
+    pub(crate) fn record_boundary(&mut self) {
+        let Some(mut rewind) = self.rewind.take() else {
+            return;
+        };
+
+        if let Some(keyframe) = rewind.keyframes.back_mut() {
+            keyframe.boundaries.push(Boundary {
+                registers: self.registers(),
+                irq_line: self.irq_line,
+                nmi_line: self.nmi_line,
+                nmi_pending: self.nmi_pending,
+                pending_interrupt: self.pending_interrupt,
+                so_line: self.so_line,
+                so_pending: self.so_pending,
+                poll_mask: self.poll_mask,
+                poll: self.poll,
+                nmi_hijacked: self.nmi_hijacked,
+                rdy: self.rdy,
+                stall_cycles: self.stall_cycles,
+                stall_align: self.stall_align,
+                cycle_debt: self.cycle_debt,
+                writes: keyframe.writes.len(),
+            });
+        }
+
+        let due = rewind
+            .keyframes
+            .back()
+            .is_none_or(|keyframe| self.cycles.saturating_sub(keyframe.cycle) >= rewind.interval);
+        if due && let Ok(snapshot) = (rewind.save)(self) {
+            rewind.keyframes.push_back(Keyframe {
+                cycle: self.cycles,
+                snapshot,
+                boundaries: Vec::new(),
+                writes: Vec::new(),
+            });
+            while rewind.keyframes.len() > rewind.limit {
+                rewind.keyframes.pop_front();
+            }
+        }
+        self.rewind = Some(rewind);
+    }
+}
@@ -0,0 +1,104 @@
+#![allow(dead_code)]
+
+use crate::bus::Memory;
+use crate::cpu::{CPU, StepOutcome, Variant};
+
+type Condition<'a, B> = Box<dyn FnMut(&CPU<B>) -> bool + 'a>;
+type Predicate<'a> = Box<dyn Fn(u8) -> bool + 'a>;
+
+// Conditions run_until() checks after every step, in the order given
+pub enum Stop<'a, B: Memory> {
+    Cycles(u64),                     // This many cycles have gone by since the call
+    Instructions(u64),               // This many instructions have run since the call
+    Pc(u32),                         // PC arrived at this address (PB:PC, bank 0 on the 8-bit chips)
+    SelfJump,                        // An instruction jumped or branched to itself (JMP *, BNE *)
+    Brk,                             // A BRK has just run
+    Memory(u16, Predicate<'a>),      // The byte at this address matches (peeked, no side effects)
+    When(Condition<'a, B>),          // The closure returned true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Cycles,
+    Instructions,
+    Pc(u32),
+    SelfJump(u32),   // Address of the instruction that looped on itself (PB:PC)
+    Brk(u32),        // Address of the BRK (PB:PC)
+    Memory(u16),
+    When,
+    Breakpoint(u32), // One of cpu.breakpoints
+    Jammed,
+    Stopped,
+}
+
+impl<B: Memory> CPU<B> {
+    // Steps until one of `stops` is met, or the CPU hits a breakpoint, jams or stops.
+    // Interrupts, WAI and RDY halts just keep it running.
+    pub fn run_until(&mut self, stops: &mut [Stop<B>]) -> StopReason {
+        let start = self.cycles;
+        let mut instructions = 0;
+
+        loop {
+            let address = ((self.program_bank as u32) << 16) | self.program_counter as u32;
+            let outcome = self.step();
+            let pc = ((self.program_bank as u32) << 16) | self.program_counter as u32;
+
+            match outcome {
+                StepOutcome::Breakpoint { address } => return StopReason::Breakpoint(address),
+                StepOutcome::Jammed => return StopReason::Jammed,
+                StepOutcome::Stopped => return StopReason::Stopped,
+                StepOutcome::Executed { .. } => instructions += 1,
+                _ => {}
+            }
+            let executed = matches!(outcome, StepOutcome::Executed { .. } | StepOutcome::Interrupt { .. });
+
+            for stop in stops.iter_mut() {
+                let reason = match stop {
+                    Stop::Cycles(budget) if self.cycles - start >= *budget => StopReason::Cycles,
+                    Stop::Instructions(count) if instructions >= *count => StopReason::Instructions,
+                    Stop::Pc(target) if executed && pc == *target => StopReason::Pc(*target),
+                    Stop::SelfJump if self.is_self_jump(outcome, address, pc) => StopReason::SelfJump(address),
+                    Stop::Brk if matches!(outcome, StepOutcome::Executed { opcode: 0x00, .. }) => StopReason::Brk(address),
+                    Stop::Memory(target, predicate) if predicate(self.bus.peek(*target)) => StopReason::Memory(*target),
+                    Stop::When(condition) => {
+                        if !condition(self) {
+                            continue;
+                        }
+                        StopReason::When
+                    }
+                    _ => continue,
+                };
+                return reason;
+            }
+        }
+    }
+
+    // Runs a frame or scanline's worth of cycles. It stops on the first instruction boundary
+    // that reaches the budget, and the overshoot comes off the next call's budget, so a
+    // frame loop stays locked to the clock over time.
+    pub fn run_for_cycles(&mut self, cycles: u64) -> StopReason {
+        if self.cycle_debt >= cycles {
+            self.cycle_debt -= cycles;
+            return StopReason::Cycles;
+        }
+
+        let budget = cycles - self.cycle_debt;
+        let start = self.cycles;
+        let reason = self.run_until(&mut [Stop::Cycles(budget)]);
+        self.cycle_debt = (self.cycles - start).saturating_sub(budget);
+        reason
+    }
+
+    // An instruction that left PB:PC where it was, so a JML to the same offset in another
+    // bank doesn't count. Neither do the 65C816 block moves, which stay put on purpose
+    // until they are done.
+    fn is_self_jump(&self, outcome: StepOutcome, address: u32, pc: u32) -> bool {
+        match outcome {
+            StepOutcome::Executed { opcode, .. } => {
+                pc == address
+                    && !(self.variant == Variant::W65C816 && matches!(opcode, 0x44 | 0x54))
+            }
+            _ => false,
+        }
+    }
+}
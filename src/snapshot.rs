@@ -0,0 +1,322 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+use crate::bus::{Bus, Memory};
+use crate::cpu::{CPU, CpuState, Interrupt, Poll, Variant};
+
+// Save states. A snapshot is a header followed by tagged chunks:
+//
+//   "R65S"   magic
+//   u16      format version
+//   chunks   4-byte tag, u32 payload length, payload
+//
+// "CPU " holds the registers and pin state, "MEM " the memory. All numbers are little-endian.
+// New state goes in new chunks or at the end of a chunk, and loading fills in defaults for
+// whatever an older snapshot lacks, so older snapshots keep loading. Loading also skips
+// chunks it doesn't know and ignores bytes past the fields it reads. The version only goes
+// up for changes older builds can't read that way, and they reject such snapshots.
+const MAGIC: &[u8; 4] = b"R65S";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const CPU_CHUNK: [u8; 4] = *b"CPU ";
+const MEMORY_CHUNK: [u8; 4] = *b"MEM ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    Truncated,
+    MissingChunk([u8; 4]),
+    BadValue(&'static str),
+    MidInstruction, // tick() is partway through an instruction
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a save state"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            SnapshotError::Truncated => write!(f, "save state is truncated"),
+            SnapshotError::MissingChunk(tag) => write!(f, "save state has no {:?} chunk", String::from_utf8_lossy(tag)),
+            SnapshotError::BadValue(field) => write!(f, "save state has an invalid {}", field),
+            SnapshotError::MidInstruction => write!(f, "can only save between instructions"),
+        }
+    }
+}
+
+// Buses that can go into a save state
+pub trait MemorySnapshot {
+    fn save_memory(&self) -> Vec<u8>;
+    fn load_memory(&mut self, data: &[u8]) -> Result<(), SnapshotError>;
+
+    // Puts a byte the CPU wrote back into the state save_memory() covers, without any
+    // side effects. Rewinding uses it to replay writes on top of a loaded snapshot.
+    fn restore_write(&mut self, address: u32, data: u8);
+}
+
+impl MemorySnapshot for Bus {
+    fn save_memory(&self) -> Vec<u8> {
+        self.memory.to_vec()
+    }
+
+    fn load_memory(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        if data.len() != self.memory.len() {
+            return Err(SnapshotError::BadValue("memory size"));
+        }
+        self.memory.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn restore_write(&mut self, address: u32, data: u8) {
+        self.memory[address as u16 as usize] = data;
+    }
+}
+
+impl<B: Memory + MemorySnapshot> CPU<B> {
+    pub fn save_state(&self) -> Result<Vec<u8>, SnapshotError> {
+        if !self.at_instruction_boundary() {
+            return Err(SnapshotError::MidInstruction);
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        write_chunk(&mut out, CPU_CHUNK, &self.save_cpu());
+        write_chunk(&mut out, MEMORY_CHUNK, &self.bus.save_memory());
+        Ok(out)
+    }
+
+    // Nothing changes unless the whole snapshot checks out
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        if data.len() < 6 || &data[..4] != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version == 0 || version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let chunks = read_chunks(&data[6..])?;
+        let cpu = find_chunk(&chunks, CPU_CHUNK)?;
+        let memory = find_chunk(&chunks, MEMORY_CHUNK)?;
+        let state = CpuSnapshot::read(&mut Reader(cpu))?;
+        self.bus.load_memory(memory)?;
+        state.apply(self);
+        self.restart_rewind();
+        Ok(())
+    }
+
+    fn save_cpu(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.push(match self.variant {
+            Variant::Nmos6502 => 0,
+            Variant::Wdc65C02 => 1,
+            Variant::Ricoh2A03 => 2,
+            Variant::W65C816 => 3,
+        });
+        out.extend_from_slice(&[self.register_a, self.register_x, self.register_y, self.status, self.stack_pointer]);
+        out.extend_from_slice(&self.program_counter.to_le_bytes());
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.push(match self.state {
+            CpuState::Running => 0,
+            CpuState::Jammed => 1,
+            CpuState::Waiting => 2,
+            CpuState::Stopped => 3,
+        });
+        out.extend_from_slice(&[self.register_b, self.register_x_high, self.register_y_high, self.stack_pointer_high]);
+        out.extend_from_slice(&self.direct_page.to_le_bytes());
+        out.extend_from_slice(&[self.data_bank, self.program_bank, self.emulation as u8]);
+
+        out.extend_from_slice(&self.irq_line.to_le_bytes());
+        out.extend_from_slice(&[self.nmi_line as u8, self.nmi_pending as u8]);
+        out.push(match self.pending_interrupt {
+            None => 0,
+            Some(Interrupt::Nmi) => 1,
+            Some(Interrupt::Irq) => 2,
+        });
+        out.extend_from_slice(&[self.poll_mask as u8, self.so_line as u8, self.rdy as u8]);
+        out.extend_from_slice(&self.stall_cycles.to_le_bytes());
+        out.push(self.stall_align as u8);
+        out.extend_from_slice(&self.cycle_debt.to_le_bytes());
+
+        out.extend_from_slice(&[self.so_pending as u8, self.nmi_hijacked as u8]);
+        out.push(match self.poll {
+            Poll::Normal => 0,
+            Poll::Early => 1,
+            Poll::Skip => 2,
+        });
+        out
+    }
+}
+
+pub(crate) fn write_chunk(out: &mut Vec<u8>, tag: [u8; 4], payload: &[u8]) {
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+pub(crate) type Chunks<'a> = Vec<([u8; 4], &'a [u8])>;
+
+// Splits the rest of a file into its chunks
+pub(crate) fn read_chunks(mut rest: &[u8]) -> Result<Chunks<'_>, SnapshotError> {
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        let mut header = Reader(rest);
+        let tag = header.bytes()?;
+        let length = header.u32()? as usize;
+        let payload = header.0.get(..length).ok_or(SnapshotError::Truncated)?;
+        chunks.push((tag, payload));
+        rest = &header.0[length..];
+    }
+    Ok(chunks)
+}
+
+// The first chunk with this tag, later ones are ignored
+pub(crate) fn find_chunk<'a>(chunks: &Chunks<'a>, tag: [u8; 4]) -> Result<&'a [u8], SnapshotError> {
+    chunks
+        .iter()
+        .find(|(found, _)| *found == tag)
+        .map(|&(_, payload)| payload)
+        .ok_or(SnapshotError::MissingChunk(tag))
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl Reader<'_> {
+    pub(crate) fn bytes<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let bytes = self.0.get(..N).ok_or(SnapshotError::Truncated)?;
+        self.0 = &self.0[N..];
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+}
+
+// The "CPU " chunk, decoded in full before any of it is applied
+struct CpuSnapshot {
+    variant: Variant,
+    registers: [u8; 5], // A, X, Y, P, SP
+    program_counter: u16,
+    cycles: u64,
+    state: CpuState,
+    high: [u8; 4],      // B, X high, Y high, SP high
+    direct_page: u16,
+    data_bank: u8,
+    program_bank: u8,
+    emulation: bool,
+    irq_line: u32,
+    nmi_line: bool,
+    nmi_pending: bool,
+    pending_interrupt: Option<Interrupt>,
+    poll_mask: bool,
+    so_line: bool,
+    rdy: bool,
+    stall_cycles: u64,
+    stall_align: bool,
+    cycle_debt: u64,
+    so_pending: bool,
+    nmi_hijacked: bool,
+    poll: Poll,
+}
+
+impl CpuSnapshot {
+    fn read(r: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(CpuSnapshot {
+            variant: match r.u8()? {
+                0 => Variant::Nmos6502,
+                1 => Variant::Wdc65C02,
+                2 => Variant::Ricoh2A03,
+                3 => Variant::W65C816,
+                _ => return Err(SnapshotError::BadValue("variant")),
+            },
+            registers: r.bytes()?,
+            program_counter: r.u16()?,
+            cycles: r.u64()?,
+            state: match r.u8()? {
+                0 => CpuState::Running,
+                1 => CpuState::Jammed,
+                2 => CpuState::Waiting,
+                3 => CpuState::Stopped,
+                _ => return Err(SnapshotError::BadValue("CPU state")),
+            },
+            high: r.bytes()?,
+            direct_page: r.u16()?,
+            data_bank: r.u8()?,
+            program_bank: r.u8()?,
+            emulation: r.bool()?,
+            irq_line: r.u32()?,
+            nmi_line: r.bool()?,
+            nmi_pending: r.bool()?,
+            pending_interrupt: match r.u8()? {
+                0 => None,
+                1 => Some(Interrupt::Nmi),
+                2 => Some(Interrupt::Irq),
+                _ => return Err(SnapshotError::BadValue("pending interrupt")),
+            },
+            poll_mask: r.bool()?,
+            so_line: r.bool()?,
+            rdy: r.bool()?,
+            stall_cycles: r.u64()?,
+            stall_align: r.bool()?,
+            cycle_debt: r.u64()?,
+
+            // Snapshots from before these were saved end here
+            so_pending: !r.0.is_empty() && r.bool()?,
+            nmi_hijacked: !r.0.is_empty() && r.bool()?,
+            poll: match if r.0.is_empty() { 0 } else { r.u8()? } {
+                0 => Poll::Normal,
+                1 => Poll::Early,
+                2 => Poll::Skip,
+                _ => return Err(SnapshotError::BadValue("interrupt poll")),
+            },
+        })
+    }
+
+    fn apply<B: Memory>(self, cpu: &mut CPU<B>) {
+        // Whatever tick() was doing belongs to the state being replaced
+        cpu.abandon_instruction();
+
+        cpu.variant = self.variant;
+        [cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer] = self.registers;
+        cpu.program_counter = self.program_counter;
+        cpu.cycles = self.cycles;
+        cpu.state = self.state;
+        [cpu.register_b, cpu.register_x_high, cpu.register_y_high, cpu.stack_pointer_high] = self.high;
+        cpu.direct_page = self.direct_page;
+        cpu.data_bank = self.data_bank;
+        cpu.program_bank = self.program_bank;
+        cpu.emulation = self.emulation;
+        cpu.irq_line = self.irq_line;
+        cpu.nmi_line = self.nmi_line;
+        cpu.nmi_pending = self.nmi_pending;
+        cpu.pending_interrupt = self.pending_interrupt;
+        cpu.poll_mask = self.poll_mask;
+        cpu.so_line = self.so_line;
+        cpu.rdy = self.rdy;
+        cpu.stall_cycles = self.stall_cycles;
+        cpu.stall_align = self.stall_align;
+        cpu.cycle_debt = self.cycle_debt;
+        cpu.so_pending = self.so_pending;
+        cpu.nmi_hijacked = self.nmi_hijacked;
+        cpu.poll = self.poll;
+    }
+}
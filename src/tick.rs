@@ -0,0 +1,183 @@
+use crate::bus::Memory;
+use crate::cpu::{CPU, CpuState, Poll, Registers, FLAG_OVERFLOW};
+
+// An instruction that tick() has started but not finished yet.
+//
+// The opcode handlers run a whole instruction in one go, so to split it into clock cycles
+// the instruction is run again from the registers it started with on every tick that has
+// a bus access. Accesses that already happened are answered from `values` instead of
+// touching the bus again, the next one goes out to the real bus, and the run is abandoned
+// at the one after that: nothing past it reaches the bus or is even peeked at, so the
+// instruction can never act on a value the bus has not really delivered yet. Every read
+// and write therefore reaches the bus once, on its own cycle, and in order.
+// A tick costs one run, and replaying the few accesses already made is cheap.
+pub struct Replay {
+    registers: Registers, // CPU state at the start of the instruction
+    values: Vec<u8>,      // Data seen by the accesses performed so far
+    accesses: usize,      // Accesses made by the current run
+    performed: bool,      // Whether the current run has made its new access
+    abandoned: bool,      // Whether the current run has gone past it
+    next_write: bool,     // Whether the first access not yet performed is a write
+    ticks: u64,           // Cycles spent on the instruction so far
+    lines: Vec<(u32, bool)>, // IRQ and NMI as they were on each cycle
+    result: Option<(Registers, u64)>, // Final state and length once every access is done
+}
+
+pub enum Access {
+    Perform,     // Go out to the bus and record the result
+    Replay(u8),  // Already happened on an earlier tick
+    Skip,        // Lies in the future, the rest of the run is thrown away
+}
+
+impl Replay {
+    fn new(registers: Registers) -> Self {
+        Replay {
+            registers,
+            values: Vec::new(),
+            accesses: 0,
+            performed: false,
+            abandoned: false,
+            next_write: false, // Every instruction starts with a read
+            ticks: 0,
+            lines: Vec::new(),
+            result: None,
+        }
+    }
+
+    pub fn next_access(&mut self, write: bool) -> Access {
+        let index = self.accesses;
+        self.accesses += 1;
+        if index < self.values.len() {
+            Access::Replay(self.values[index])
+        } else if !self.performed {
+            self.performed = true;
+            Access::Perform
+        } else {
+            if !self.abandoned {
+                self.abandoned = true;
+                self.next_write = write;
+            }
+            Access::Skip
+        }
+    }
+
+    pub fn record(&mut self, data: u8) {
+        self.values.push(data);
+    }
+
+    // The latched NMI on a cycle (counting from 1) that has already been clocked
+    pub fn nmi_pending_on(&self, cycle: usize) -> Option<bool> {
+        self.lines.get(cycle.checked_sub(1)?).map(|&(_, nmi_pending)| nmi_pending)
+    }
+}
+
+impl<B: Memory> CPU<B> {
+    // Advances exactly one clock cycle. Returns false once the CPU has jammed or stopped.
+    //
+    // Each cycle carries at most one bus access. The few cycles an instruction spends
+    // without an access of its own are idle, and come after its last access. The registers
+    // keep their old values until the instruction's last cycle, and cycles spent halted by
+    // RDY or a DMA stall push the rest of the instruction back.
+    pub fn tick(&mut self) -> bool {
+        self.feed_inputs();
+
+        if self.replay.is_none() {
+            if matches!(self.state, CpuState::Jammed | CpuState::Stopped) {
+                return false;
+            }
+            if !self.ready() {
+                self.cycles += 1;
+                return true;
+            }
+            // The opcode fetch is a read, so RDY and DMA can hold it up
+            if self.halt_cycle(false) {
+                return true;
+            }
+        }
+
+        let mut replay = self.replay.take().unwrap_or_else(|| Replay::new(self.registers()));
+        self.cycles = replay.registers.cycles + replay.ticks;
+
+        // A halted cycle pushes the rest of the instruction back by one. Idle cycles
+        // count as reads.
+        let write = replay.result.is_none() && replay.next_write;
+        if self.halt_cycle(write) {
+            replay.registers.cycles += 1;
+            self.replay = Some(replay);
+            return true;
+        }
+
+        replay.ticks += 1;
+        replay.lines.push((self.irq_line, self.nmi_pending));
+
+        // Perform the next access. A run that gets to the end without being abandoned
+        // has made the last one, and leaves the instruction's result behind.
+        if replay.result.is_none() {
+            let cycles = self.replay_instruction(&mut replay);
+            if !replay.abandoned {
+                replay.result = Some((self.registers(), cycles));
+            }
+            self.set_registers(replay.registers);
+        }
+
+        if let Some((registers, cycles)) = replay.result
+            && replay.ticks >= cycles
+        {
+            self.set_registers(registers);
+            self.cycles = replay.registers.cycles + replay.ticks;
+            if self.so_pending {
+                self.so_pending = false;
+                self.status |= FLAG_OVERFLOW;
+            }
+
+            // Interrupts are polled on the second to last cycle, so a line that changes
+            // during the last one is only noticed at the end of the next instruction
+            let poll_cycle = if self.poll == Poll::Early { 3 } else { 2 };
+            let (irq_line, nmi_pending) =
+                replay.lines[replay.lines.len().saturating_sub(poll_cycle)];
+            self.complete_instruction(irq_line, nmi_pending);
+            return !matches!(self.state, CpuState::Jammed | CpuState::Stopped);
+        }
+
+        self.cycles = replay.registers.cycles + replay.ticks;
+        self.replay = Some(replay);
+        true
+    }
+
+    // Cycle count the instruction tick() is partway through started on
+    pub(crate) fn instruction_start(&self) -> Option<u64> {
+        self.replay.as_ref().map(|replay| replay.registers.cycles)
+    }
+
+    // True between instructions, false while tick() is partway through one
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.replay.is_none()
+    }
+
+    // Runs the instruction from its starting registers up to its next new access, returning
+    // how many cycles it took. The registers are left as the run produced them, which only
+    // means something when it was not abandoned.
+    fn replay_instruction(&mut self, replay: &mut Replay) -> u64 {
+        self.set_registers(replay.registers);
+        replay.accesses = 0;
+        replay.performed = false;
+        replay.abandoned = false;
+
+        self.replay = Some(std::mem::replace(replay, Replay::new(replay.registers)));
+        self.execute_instruction();
+        *replay = self.replay.take().unwrap();
+
+        self.cycles - replay.registers.cycles
+    }
+
+    // Finishes an instruction started by tick(), so the CPU is back on an instruction boundary
+    // With RDY held low it gives up after one cycle, as the instruction cannot move on.
+    pub fn finish_instruction(&mut self) {
+        while self.replay.is_some() {
+            self.tick();
+            if !self.rdy {
+                break;
+            }
+        }
+    }
+}
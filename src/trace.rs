@@ -1,23 +1,46 @@
-use crate::cpu::CPU;
+use crate::bus::Memory;
+use crate::cpu::{CPU, Variant};
 
-pub fn trace(cpu: &mut CPU) -> String {
+pub fn trace<B: Memory>(cpu: &CPU<B>) -> String {
     let pc = cpu.program_counter;
-    let opcode = cpu.bus.read(pc);
+    let bank = (cpu.program_bank as u32) << 16;
+    let byte = |offset: u16| cpu.bus.peek_long(bank | pc.wrapping_add(offset) as u32);
+    let opcode = byte(0);
 
-    let (mnemonic, len) = get_opcode_info(opcode);
+    let (mnemonic, len) = match cpu.variant {
+        Variant::Wdc65C02 => get_65c02_opcode_info(opcode).unwrap_or_else(|| get_opcode_info(opcode)),
+        Variant::Nmos6502 | Variant::Ricoh2A03 => get_opcode_info(opcode),
+        Variant::W65C816 => get_65c816_opcode_info(cpu, opcode),
+    };
 
     let mut hex_dump = String::new();
     for i in 0..len {
-        hex_dump.push_str(&format!("{:02X} ", cpu.bus.read(pc.wrapping_add(i as u16))));
+        hex_dump.push_str(&format!("{:02X} ", byte(i as u16)));
     }
 
+    // BBR/BBS carry two operands: a zero page address and a branch offset
+    let zero_page_relative = cpu.variant == Variant::Wdc65C02 && opcode & 0x0F == 0x0F;
+    // MVN/MVP encode the destination bank first but are written source first
+    let block_move = cpu.variant == Variant::W65C816 && (opcode == 0x44 || opcode == 0x54);
+
     let asm_string = match len {
-        1 => format!("{}", mnemonic),
-        2 => format!("{} ${:02X}", mnemonic, cpu.bus.read(pc.wrapping_add(1))),
-        3 => format!("{} ${:02X}{:02X}", mnemonic, cpu.bus.read(pc.wrapping_add(2)), cpu.bus.read(pc.wrapping_add(1))),
+        3 if zero_page_relative => format!("{} ${:02X},${:02X}", mnemonic, byte(1), byte(2)),
+        3 if block_move => format!("{} ${:02X},${:02X}", mnemonic, byte(2), byte(1)),
+        1 => mnemonic.to_string(),
+        2 => format!("{} ${:02X}", mnemonic, byte(1)),
+        3 => format!("{} ${:02X}{:02X}", mnemonic, byte(2), byte(1)),
+        4 => format!("{} ${:02X}{:02X}{:02X}", mnemonic, byte(3), byte(2), byte(1)),
         _ => String::from("???"),
     };
 
+    if cpu.variant == Variant::W65C816 {
+        return format!(
+            "{:02X}:{:04X}  {:11} {:>15} A:{:02X}{:02X} X:{:04X} Y:{:04X} P:{:02X} SP:{:04X} D:{:04X} DB:{:02X} E:{}",
+            cpu.program_bank, pc, hex_dump.trim(), asm_string, cpu.register_b, cpu.register_a, cpu.index_x(),
+            cpu.index_y(), cpu.status, cpu.stack_address(), cpu.direct_page, cpu.data_bank, cpu.emulation as u8
+        );
+    }
+
     format!(
         "{:04X}  {:8} {:>14} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
         pc, hex_dump.trim(), asm_string, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer
@@ -85,6 +108,115 @@ fn get_opcode_info(opcode: u8) -> (&'static str, u8) {
             (mnemonic, 3)
         }
         
-        _ => ("*ILG", 1), // Illegal / Unimplemented opcode fallback
+        // Undocumented NMOS opcodes
+        0x1A|0x3A|0x5A|0x7A|0xDA|0xFA => ("*NOP", 1),
+        0x02|0x12|0x22|0x32|0x42|0x52|0x62|0x72|0x92|0xB2|0xD2|0xF2 => ("*JAM", 1),
+
+        0x03|0x07|0x13|0x17|0x23|0x27|0x33|0x37|0x43|0x47|0x53|0x57|0x63|0x67|0x73|0x77|
+        0x83|0x87|0x97|0xA3|0xA7|0xB3|0xB7|0xC3|0xC7|0xD3|0xD7|0xE3|0xE7|0xF3|0xF7|0x93|
+        0x0B|0x2B|0x4B|0x6B|0x8B|0xAB|0xCB|0xEB|0x80|0x82|0x89|0xC2|0xE2|0x04|0x44|0x64|
+        0x14|0x34|0x54|0x74|0xD4|0xF4 => {
+            let mnemonic = match opcode {
+                0x03|0x07|0x13|0x17 => "*SLO", 0x23|0x27|0x33|0x37 => "*RLA",
+                0x43|0x47|0x53|0x57 => "*SRE", 0x63|0x67|0x73|0x77 => "*RRA",
+                0x83|0x87|0x97 => "*SAX", 0xA3|0xA7|0xB3|0xB7 => "*LAX",
+                0xC3|0xC7|0xD3|0xD7 => "*DCP", 0xE3|0xE7|0xF3|0xF7 => "*ISC",
+                0x93 => "*SHA",
+                0x0B|0x2B => "*ANC", 0x4B => "*ALR", 0x6B => "*ARR", 0x8B => "*ANE",
+                0xAB => "*LXA", 0xCB => "*SBX", 0xEB => "*SBC",
+                _ => "*NOP"
+            };
+            (mnemonic, 2)
+        }
+
+        0x0F|0x1B|0x1F|0x2F|0x3B|0x3F|0x4F|0x5B|0x5F|0x6F|0x7B|0x7F|0x8F|0xAF|0xBF|0xCF|
+        0xDB|0xDF|0xEF|0xFB|0xFF|0xBB|0x9B|0x9C|0x9E|0x9F|0x0C|0x1C|0x3C|0x5C|0x7C|0xDC|
+        0xFC => {
+            let mnemonic = match opcode {
+                0x0F|0x1B|0x1F => "*SLO", 0x2F|0x3B|0x3F => "*RLA",
+                0x4F|0x5B|0x5F => "*SRE", 0x6F|0x7B|0x7F => "*RRA",
+                0x8F => "*SAX", 0xAF|0xBF => "*LAX",
+                0xCF|0xDB|0xDF => "*DCP", 0xEF|0xFB|0xFF => "*ISC",
+                0xBB => "*LAS", 0x9B => "*TAS", 0x9C => "*SHY", 0x9E => "*SHX", 0x9F => "*SHA",
+                _ => "*NOP"
+            };
+            (mnemonic, 3)
+        }
+    }
+}
+
+const RMB: [&str; 8] = ["RMB0", "RMB1", "RMB2", "RMB3", "RMB4", "RMB5", "RMB6", "RMB7"];
+const SMB: [&str; 8] = ["SMB0", "SMB1", "SMB2", "SMB3", "SMB4", "SMB5", "SMB6", "SMB7"];
+const BBR: [&str; 8] = ["BBR0", "BBR1", "BBR2", "BBR3", "BBR4", "BBR5", "BBR6", "BBR7"];
+const BBS: [&str; 8] = ["BBS0", "BBS1", "BBS2", "BBS3", "BBS4", "BBS5", "BBS6", "BBS7"];
+
+// Opcodes the 65C02 added or redefined, None for the ones shared with the NMOS 6502
+fn get_65c02_opcode_info(opcode: u8) -> Option<(&'static str, u8)> {
+    let info = match opcode {
+        0x1A => ("INC A", 1), 0x3A => ("DEC A", 1),
+        0x5A => ("PHY", 1), 0x7A => ("PLY", 1), 0xDA => ("PHX", 1), 0xFA => ("PLX", 1),
+
+        0x12 => ("ORA", 2), 0x32 => ("AND", 2), 0x52 => ("EOR", 2), 0x72 => ("ADC", 2),
+        0x92 => ("STA", 2), 0xB2 => ("LDA", 2), 0xD2 => ("CMP", 2), 0xF2 => ("SBC", 2),
+        0x04 | 0x0C => ("TSB", if opcode == 0x04 { 2 } else { 3 }),
+        0x14 | 0x1C => ("TRB", if opcode == 0x14 { 2 } else { 3 }),
+        0x34 | 0x89 => ("BIT", 2), 0x3C => ("BIT", 3),
+        0x64 | 0x74 => ("STZ", 2), 0x9C | 0x9E => ("STZ", 3),
+        0x80 => ("BRA", 2),
+        0x7C => ("JMP", 3),
+
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 | 0x44 | 0x54 | 0xD4 | 0xF4 => ("NOP", 2),
+        0x5C | 0xDC | 0xFC => ("NOP", 3),
+        0xCB => ("WAI", 1), 0xDB => ("STP", 1),
+        _ if opcode & 0x0F == 0x07 => {
+            let bit = ((opcode >> 4) & 0x07) as usize;
+            (if opcode & 0x80 != 0 { SMB[bit] } else { RMB[bit] }, 2)
+        }
+        _ if opcode & 0x0F == 0x0F => {
+            let bit = ((opcode >> 4) & 0x07) as usize;
+            (if opcode & 0x80 != 0 { BBS[bit] } else { BBR[bit] }, 3)
+        }
+        _ if opcode & 0x03 == 0x03 => ("NOP", 1),
+
+        _ => return None,
+    };
+    Some(info)
+}
+
+const ALU: [&str; 8] = ["ORA", "AND", "EOR", "ADC", "STA", "LDA", "CMP", "SBC"];
+
+// The 65C816 fills every opcode the 65C02 left undefined. Immediate operands are
+// one byte longer while the matching register is 16 bits wide.
+fn get_65c816_opcode_info<B: Memory>(cpu: &CPU<B>, opcode: u8) -> (&'static str, u8) {
+    let wide_memory = !cpu.memory_8bit();
+    let wide_index = !cpu.index_8bit();
+
+    match opcode {
+        0x09 | 0x29 | 0x49 | 0x69 | 0x89 | 0xA9 | 0xC9 | 0xE9 if wide_memory => {
+            let (mnemonic, _) = get_65c02_opcode_info(opcode).unwrap_or_else(|| get_opcode_info(opcode));
+            (mnemonic, 3)
+        }
+        0xA0 | 0xA2 | 0xC0 | 0xE0 if wide_index => (get_opcode_info(opcode).0, 3),
+
+        0x02 => ("COP", 2), 0x42 => ("WDM", 2),
+        0xC2 => ("REP", 2), 0xE2 => ("SEP", 2),
+        0x62 => ("PER", 3), 0x82 => ("BRL", 3),
+        0xD4 => ("PEI", 2), 0xF4 => ("PEA", 3),
+        0x44 => ("MVP", 3), 0x54 => ("MVN", 3),
+        0x22 => ("JSL", 4), 0x5C => ("JML", 4), 0xDC => ("JML", 3), 0xFC => ("JSR", 3),
+
+        0x0B => ("PHD", 1), 0x1B => ("TCS", 1), 0x2B => ("PLD", 1), 0x3B => ("TSC", 1),
+        0x4B => ("PHK", 1), 0x5B => ("TCD", 1), 0x6B => ("RTL", 1), 0x7B => ("TDC", 1),
+        0x8B => ("PHB", 1), 0x9B => ("TXY", 1), 0xAB => ("PLB", 1), 0xBB => ("TYX", 1),
+        0xEB => ("XBA", 1), 0xFB => ("XCE", 1),
+
+        // d,S and (d,S),Y
+        _ if opcode & 0x0F == 0x03 => (ALU[(opcode >> 5) as usize], 2),
+        // [d] and [d],Y
+        _ if opcode & 0x0F == 0x07 => (ALU[(opcode >> 5) as usize], 2),
+        // al and al,X
+        _ if opcode & 0x0F == 0x0F => (ALU[(opcode >> 5) as usize], 4),
+
+        _ => get_65c02_opcode_info(opcode).unwrap_or_else(|| get_opcode_info(opcode)),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,220 @@
+use crate::cpu::{CPU, InterruptSource, FLAG_ZERO, FLAG_NEGATIVE, FLAG_CARRY, FLAG_INDEX_WIDTH, FLAG_MEMORY_WIDTH};
+use crate::bus::Memory;
+use crate::addressing::{AddressingMode, direct_address, direct_offset, internal_cycle};
+use crate::opcodes::{self, pull_value, push_value, set_accumulator, set_index_x, set_index_y, set_stack, set_status,
+                     stack_dummy_read, update_zero_and_negative_flags};
+
+// Opcodes the 65C816 added on top of the 65C02, and the long and stack relative forms of
+// the ALU instructions. Returns false for the ones it shares with the 65C02, which run
+// through opcodes.rs at the widths M and X select.
+pub fn execute<B: Memory>(cpu: &mut CPU<B>, opcode: u8) -> bool {
+    // The single byte xB opcodes read the next byte and throw it away, like the x8 ones
+    if opcode & 0x0F == 0x0B && !matches!(opcode, 0xCB | 0xDB) {
+        cpu.read_program(cpu.program_counter);
+    }
+
+    match opcode {
+        // COP, BRK goes through opcodes.rs
+        0x02 => cpu.interrupt_sequence(InterruptSource::Cop),
+
+        // ORA AND EOR ADC STA LDA CMP SBC, selected by the top three bits
+        _ if matches!(opcode & 0x1F, 0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1F) => {
+            let mode = match opcode & 0x1F {
+                0x03 => AddressingMode::StackRelative,
+                0x13 => AddressingMode::StackRelativeIndirectY,
+                0x07 => AddressingMode::DirectIndirectLong,
+                0x17 => AddressingMode::DirectIndirectLongY,
+                0x0F => AddressingMode::AbsoluteLong,
+                _ => AddressingMode::AbsoluteLongX,
+            };
+            match opcode >> 5 {
+                0 => opcodes::ora(cpu, &mode),
+                1 => opcodes::and(cpu, &mode),
+                2 => opcodes::eor(cpu, &mode),
+                3 => opcodes::adc(cpu, &mode),
+                4 => opcodes::sta(cpu, &mode),
+                5 => opcodes::lda(cpu, &mode),
+                6 => opcodes::cmp(cpu, &mode),
+                _ => opcodes::sbc(cpu, &mode),
+            }
+        }
+
+        // Long branches, jumps and subroutines
+        0x82 => brl(cpu),
+        0x5C => jml(cpu),
+        0xDC => jml_indirect(cpu),
+        0xFC => jsr_indexed_indirect(cpu),
+        0x22 => jsl(cpu),
+        0x6B => rtl(cpu),
+
+        // Stack
+        0x0B => push_value(cpu, cpu.direct_page, true),
+        0x8B => push_value(cpu, cpu.data_bank as u16, false),
+        0x4B => push_value(cpu, cpu.program_bank as u16, false),
+        0x2B => {
+            stack_dummy_read(cpu);
+            cpu.direct_page = pull_value(cpu, true);
+            update_zero_and_negative_flags(cpu, cpu.direct_page, true);
+        }
+        0xAB => {
+            stack_dummy_read(cpu);
+            cpu.data_bank = pull_value(cpu, false) as u8;
+            update_zero_and_negative_flags(cpu, cpu.data_bank as u16, false);
+        }
+        0xF4 => pea(cpu),
+        0xD4 => pei(cpu),
+        0x62 => per(cpu),
+
+        // Transfers
+        0x9B => set_index_y(cpu, cpu.index_x()),
+        0xBB => set_index_x(cpu, cpu.index_y()),
+        0x1B => set_stack(cpu, cpu.accumulator()),
+        0x3B => {
+            let value = cpu.stack_address();
+            set_accumulator(cpu, value, true);
+            update_zero_and_negative_flags(cpu, value, true);
+        }
+        0x5B => {
+            cpu.direct_page = cpu.accumulator();
+            update_zero_and_negative_flags(cpu, cpu.direct_page, true);
+        }
+        0x7B => {
+            set_accumulator(cpu, cpu.direct_page, true);
+            update_zero_and_negative_flags(cpu, cpu.direct_page, true);
+        }
+        0xEB => {
+            internal_cycle(cpu);
+            std::mem::swap(&mut cpu.register_a, &mut cpu.register_b);
+            update_zero_and_negative_flags(cpu, cpu.register_a as u16, false);
+        }
+
+        // Flags
+        0xC2 => {
+            let mask = cpu.get_operand();
+            internal_cycle(cpu);
+            set_status(cpu, cpu.status & !mask);
+        }
+        0xE2 => {
+            let mask = cpu.get_operand();
+            internal_cycle(cpu);
+            set_status(cpu, cpu.status | mask);
+        }
+        0xFB => xce(cpu),
+
+        // Block moves
+        0x54 => block_move(cpu, 1),
+        0x44 => block_move(cpu, -1),
+
+        // WDM (reserved, skips its signature byte)
+        0x42 => { cpu.get_operand(); }
+
+        _ => return false,
+    }
+    true
+}
+
+fn xce<B: Memory>(cpu: &mut CPU<B>) {
+    let carry = cpu.has_flag(FLAG_CARRY);
+    cpu.set_flag(FLAG_CARRY, cpu.emulation);
+    cpu.emulation = carry;
+
+    if cpu.emulation {
+        cpu.stack_pointer_high = 0x01;
+        set_status(cpu, cpu.status);
+    } else {
+        // Native mode starts out with 8-bit registers
+        cpu.status |= FLAG_MEMORY_WIDTH | FLAG_INDEX_WIDTH;
+    }
+}
+
+fn brl<B: Memory>(cpu: &mut CPU<B>) {
+    let offset = cpu.fetch_u16();
+    internal_cycle(cpu);
+    cpu.program_counter = cpu.program_counter.wrapping_add(offset);
+}
+
+fn jml<B: Memory>(cpu: &mut CPU<B>) {
+    let addr = cpu.fetch_u16();
+    cpu.program_bank = cpu.get_operand();
+    cpu.program_counter = addr;
+}
+
+fn jml_indirect<B: Memory>(cpu: &mut CPU<B>) {
+    let ptr = cpu.fetch_u16();
+    let lo = cpu.read(ptr) as u16;
+    let hi = cpu.read(ptr.wrapping_add(1)) as u16;
+    cpu.program_bank = cpu.read(ptr.wrapping_add(2));
+    cpu.program_counter = (hi << 8) | lo;
+}
+
+// JSR (a,X) pushes the return address between the two bytes of the table address
+fn jsr_indexed_indirect<B: Memory>(cpu: &mut CPU<B>) {
+    let lo = cpu.get_operand() as u16;
+    push_value(cpu, cpu.program_counter, true);
+    let hi = cpu.get_operand() as u16;
+    internal_cycle(cpu);
+
+    let program_bank = (cpu.program_bank as u32) << 16;
+    let ptr = ((hi << 8) | lo).wrapping_add(cpu.index_x());
+    let target_lo = cpu.read_long(program_bank | ptr as u32) as u16;
+    let target_hi = cpu.read_long(program_bank | ptr.wrapping_add(1) as u32) as u16;
+    cpu.program_counter = (target_hi << 8) | target_lo;
+}
+
+fn jsl<B: Memory>(cpu: &mut CPU<B>) {
+    let target_addr = cpu.fetch_u16();
+    cpu.push_stack(cpu.program_bank);
+    internal_cycle(cpu);
+    cpu.program_bank = cpu.get_operand();
+    push_value(cpu, cpu.program_counter.wrapping_sub(1), true);
+    cpu.program_counter = target_addr;
+}
+
+fn rtl<B: Memory>(cpu: &mut CPU<B>) {
+    stack_dummy_read(cpu);
+    cpu.program_counter = pull_value(cpu, true).wrapping_add(1);
+    cpu.program_bank = cpu.pop_stack();
+}
+
+fn pea<B: Memory>(cpu: &mut CPU<B>) {
+    let value = cpu.fetch_u16();
+    push_value(cpu, value, true);
+}
+
+fn pei<B: Memory>(cpu: &mut CPU<B>) {
+    let offset = direct_offset(cpu);
+    let lo = cpu.read(direct_address(cpu, offset) as u16) as u16;
+    let hi = cpu.read(direct_address(cpu, offset.wrapping_add(1)) as u16) as u16;
+    push_value(cpu, (hi << 8) | lo, true);
+}
+
+fn per<B: Memory>(cpu: &mut CPU<B>) {
+    let offset = cpu.fetch_u16();
+    internal_cycle(cpu);
+    let value = cpu.program_counter.wrapping_add(offset);
+    push_value(cpu, value, true);
+}
+
+// MVN/MVP move one byte per execution and rewind the PC until C wraps to $FFFF,
+// so interrupts can still be taken in the middle of a long move
+fn block_move<B: Memory>(cpu: &mut CPU<B>, step: i16) {
+    let dest_bank = cpu.get_operand();
+    let src_bank = cpu.get_operand();
+    cpu.data_bank = dest_bank;
+
+    let value = cpu.read_long(((src_bank as u32) << 16) | cpu.index_x() as u32);
+    cpu.write_long(((dest_bank as u32) << 16) | cpu.index_y() as u32, value);
+    internal_cycle(cpu);
+    internal_cycle(cpu);
+
+    let flags = cpu.status & (FLAG_ZERO | FLAG_NEGATIVE);
+    set_index_x(cpu, cpu.index_x().wrapping_add_signed(step));
+    set_index_y(cpu, cpu.index_y().wrapping_add_signed(step));
+    cpu.status = (cpu.status & !(FLAG_ZERO | FLAG_NEGATIVE)) | flags;
+
+    let count = cpu.accumulator().wrapping_sub(1);
+    set_accumulator(cpu, count, true);
+    if count != 0xFFFF {
+        cpu.program_counter = cpu.program_counter.wrapping_sub(3);
+    }
+}